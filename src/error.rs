@@ -18,4 +18,6 @@ pub enum OsError {
     ArgumentTooLong = -11,
     InvalidFileMode = -12,
     FileNotOpened = -13,
+    OutOfMemory = -14,
+    BrokenPipe = -15,
 }
@@ -3,12 +3,13 @@
 
 pub mod disk;
 pub mod inmem;
+pub mod pipe;
 
 use alloc::sync::Arc;
 
 use crate::io::{Read, Seek, Write};
 use crate::sync::Mutex;
-use crate::Result;
+use crate::{OsError, Result};
 
 /* -------------------------------------------------------------------------- */
 /*                                 File System                                */
@@ -58,6 +59,11 @@ pub trait Vnode: Sync + Send {
     fn len(&self) -> usize;
     fn resize(&self, size: usize) -> Result<()>;
     fn close(&self);
+
+    /// Whether it's a regular file, whose bytes can be read at any offset
+    fn is_regular(&self) -> bool {
+        true
+    }
 }
 
 /* -------------------------------------------------------------------------- */
@@ -67,11 +73,12 @@ pub trait Vnode: Sync + Send {
 /// A file descriptor, binding with a [`Vnode`], that has
 /// independent position and permissions. It provides basic
 /// file I/O interface.
-#[derive(Clone)]
 pub struct File {
     vnode: Arc<dyn Vnode>,
     pos: usize,
     deny_write: bool,
+    readable: bool,
+    writable: bool,
 }
 
 impl File {
@@ -82,10 +89,27 @@ impl File {
     pub fn inum(&self) -> usize {
         self.vnode.inum()
     }
+
+    pub fn is_regular(&self) -> bool {
+        self.vnode.is_regular()
+    }
+
+    /// Reads into `buf` starting at `off`, leaving the cursor untouched.
+    pub fn read_at(&self, buf: &mut [u8], off: usize) -> Result<usize> {
+        self.vnode.read_at(buf, off)
+    }
+
+    /// Writes `buf` starting at `off`, leaving the cursor untouched.
+    pub fn write_at(&self, buf: &[u8], off: usize) -> Result<usize> {
+        self.vnode.write_at(buf, off)
+    }
 }
 
 impl Read for File {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if !self.readable {
+            return Err(OsError::InvalidFileMode);
+        }
         let cnt = self.vnode.read_at(buf, self.pos)?;
         self.pos += cnt;
         Ok(cnt)
@@ -94,6 +118,9 @@ impl Read for File {
 
 impl Write for File {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if !self.writable {
+            return Err(OsError::InvalidFileMode);
+        }
         let cnt = self.vnode.write_at(buf, self.pos)?;
         self.pos += cnt;
         Ok(cnt)
@@ -120,15 +147,41 @@ impl File {
             vnode,
             pos: 0,
             deny_write: false,
+            readable: true,
+            writable: true,
         }
     }
 
+    /// Restricts reads and writes through this file, which are both allowed
+    /// by default.
+    pub fn set_mode(&mut self, readable: bool, writable: bool) {
+        self.readable = readable;
+        self.writable = writable;
+    }
+
     pub fn deny_write(&mut self) {
         self.deny_write = true;
         self.vnode.deny_write();
     }
 }
 
+impl Clone for File {
+    fn clone(&self) -> Self {
+        // Every denying `File` releases its own denial when dropped.
+        if self.deny_write {
+            self.vnode.deny_write();
+        }
+
+        Self {
+            vnode: self.vnode.clone(),
+            pos: self.pos,
+            deny_write: self.deny_write,
+            readable: self.readable,
+            writable: self.writable,
+        }
+    }
+}
+
 impl Drop for File {
     fn drop(&mut self) {
         if self.deny_write {
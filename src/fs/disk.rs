@@ -89,6 +89,9 @@ impl FileSys for DiskFs {
     type Path = Path;
 
     fn mount(device: Self::Device) -> Result<Self> {
+        #[cfg(feature = "mem-trace")]
+        let _tag = crate::mem::trace::Tag::new("fs");
+
         let capacity = device.lock().capacity();
         let inode_table = Mutex::new(BTreeMap::new());
         let free_map = Mutex::new({
@@ -136,6 +139,9 @@ impl FileSys for DiskFs {
     }
 
     fn create(&self, id: Self::Path) -> Result<super::File> {
+        #[cfg(feature = "mem-trace")]
+        let _tag = crate::mem::trace::Tag::new("fs");
+
         let vnode = if self.root_dir.lock().exists(&id) {
             let inum = self.root_dir.lock().path2inum(&id).unwrap();
             let vnode =
@@ -165,6 +171,9 @@ impl FileSys for DiskFs {
     }
 
     fn open(&self, id: Self::Path) -> Result<super::File> {
+        #[cfg(feature = "mem-trace")]
+        let _tag = crate::mem::trace::Tag::new("fs");
+
         if !self.root_dir.lock().exists(&id) {
             return Err(OsError::NoSuchFile);
         }
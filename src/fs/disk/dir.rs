@@ -1,12 +1,20 @@
 //! Root dir.
 //!
+use core::mem::size_of;
+use core::slice;
+
 use super::{Inum, Path};
 use crate::fs::File;
 use crate::io::prelude::*;
+use crate::mem::slab::{Cache, CacheBox};
+use crate::sync::Lazy;
 use crate::{OsError, Result};
 
 const FILE_NAME_LEN_MAX: usize = 28;
 
+/// Buffers that entries are read into.
+static ENTRIES: Lazy<Cache<DirEntry>> = Lazy::new(|| Cache::new("dir entry"));
+
 /// 32-byte entry.
 #[repr(C)]
 pub struct DirEntry {
@@ -22,17 +30,42 @@ impl DirEntry {
     pub fn invalidate(&mut self) {
         self.name[0] = '#' as u8
     }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const _ as *const u8, size_of::<Self>()) }
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self as *mut _ as *mut u8, size_of::<Self>()) }
+    }
 }
 
 /// Currently only support root dir. Other files should not be dir.
 pub struct RootDir(pub(super) File);
 
 impl RootDir {
+    /// A buffer to read entries into.
+    fn buffer() -> Result<CacheBox<DirEntry>> {
+        CacheBox::try_new(
+            &ENTRIES,
+            DirEntry {
+                name: [0; FILE_NAME_LEN_MAX],
+                inum: 0,
+            },
+        )
+    }
+
+    /// Reads the entry at the cursor into `entry`.
+    fn read_entry(&mut self, entry: &mut DirEntry) -> Result<()> {
+        self.0.read_exact(entry.as_bytes_mut())
+    }
+
     /// Convert a path to inumber. This will iteratively search through the
     /// root dir entries, return the first entry that with the same name of given one.
     pub fn path2inum(&mut self, path: &Path) -> Result<Inum> {
         self.0.rewind()?;
-        while let Ok(entry) = self.0.read_into::<DirEntry>() {
+        let mut entry = Self::buffer()?;
+        while self.read_entry(&mut entry).is_ok() {
             if !entry.is_valid() {
                 continue;
             }
@@ -70,18 +103,19 @@ impl RootDir {
         entry.name[0..core::cmp::min(path.len(), FILE_NAME_LEN_MAX - 1)]
             .copy_from_slice(path.as_bytes());
         self.0.seek(SeekFrom::Start(pos))?;
-        self.0.write_from(entry)?;
+        self.0.write_all(entry.as_bytes())?;
         Ok(())
     }
 
     /// Remove an entry from root dir by given inumber.
     pub fn remove(&mut self, inum: Inum) -> Result<()> {
         self.0.rewind()?;
-        while let Ok(mut entry) = self.0.read_into::<DirEntry>() {
+        let mut entry = Self::buffer()?;
+        while self.read_entry(&mut entry).is_ok() {
             if entry.inum == inum {
                 entry.invalidate();
                 self.0.seek(SeekFrom::Current(-32))?;
-                self.0.write_from(entry)?;
+                self.0.write_all(entry.as_bytes())?;
             }
         }
         // Ignore unexisting file.
@@ -91,7 +125,8 @@ impl RootDir {
     /// Find the first invalid place of entry. We may use it to insert a new one later.
     fn first_invalid(&mut self) -> Result<usize> {
         self.0.rewind()?;
-        while let Ok(entry) = self.0.read_into::<DirEntry>() {
+        let mut entry = Self::buffer()?;
+        while self.read_entry(&mut entry).is_ok() {
             if !entry.is_valid() {
                 return Ok(self.0.seek(SeekFrom::Current(-32)).unwrap());
             }
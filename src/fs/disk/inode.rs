@@ -8,13 +8,17 @@ use core::{cmp, mem};
 use super::{bytes_to_sectors, Inum, DISKFS};
 use crate::device::virtio::{Virtio, SECTOR_SIZE};
 use crate::fs::Vnode;
+use crate::mem::slab::{Cache, CacheBox};
 use crate::mem::{Translate, PG_MASK, PG_SIZE};
-use crate::sync::Mutex;
+use crate::sync::{Lazy, Mutex};
 use crate::{OsError, Result};
 
 const INODE_PADDING: usize = SECTOR_SIZE - core::mem::size_of::<DiskInodeInner>();
 const INODE_MAGIC: u32 = 0x494e4f44;
 
+/// Disk inodes of all inodes in memory.
+static DISK_INODES: Lazy<Cache<DiskInode>> = Lazy::new(|| Cache::new("disk inode"));
+
 /// An inode on the disk.
 ///
 /// Size of this must be `SECTOR_SIZE`.
@@ -64,7 +68,7 @@ impl InodeDesc {
 }
 
 /// Wrapper of in memory inode.
-pub struct Inode(Mutex<(InodeDesc, DiskInode)>);
+pub struct Inode(Mutex<(InodeDesc, CacheBox<DiskInode>)>);
 
 impl Inode {
     /// Tag to remove the inode on drop.
@@ -79,16 +83,19 @@ impl Inode {
     pub fn create(sector: Inum, start: Inum, len: usize) -> Result<Arc<Self>> {
         // Create file on the disk.
         let sector_num = bytes_to_sectors(len);
-        let disk_inode = DiskInode {
-            inner: DiskInodeInner {
-                start: start as _,
-                len: len as _,
-                magic: INODE_MAGIC,
+        let disk_inode = CacheBox::try_new(
+            &DISK_INODES,
+            DiskInode {
+                inner: DiskInodeInner {
+                    start: start as _,
+                    len: len as _,
+                    magic: INODE_MAGIC,
+                },
+                padding: [0; INODE_PADDING],
             },
-            padding: [0; INODE_PADDING],
-        };
+        )?;
         unsafe {
-            Virtio::write_sector(sector as _, mem::transmute(&disk_inode));
+            Virtio::write_sector(sector as _, mem::transmute(&*disk_inode));
         }
 
         // Zero the file.
@@ -108,16 +115,19 @@ impl Inode {
     /// - `Err(InvalidInode)`: failed, specifically, the inode magic is incorrect.
     pub fn open(sector: Inum) -> Result<Arc<Self>> {
         let desc = InodeDesc::new(sector, 0);
-        let mut data = DiskInode {
-            inner: DiskInodeInner {
-                start: 0,
-                len: 0,
-                magic: 0,
+        let mut data = CacheBox::try_new(
+            &DISK_INODES,
+            DiskInode {
+                inner: DiskInodeInner {
+                    start: 0,
+                    len: 0,
+                    magic: 0,
+                },
+                padding: [0; INODE_PADDING],
             },
-            padding: [0; INODE_PADDING],
-        };
+        )?;
         unsafe {
-            Virtio::read_sector(sector as _, mem::transmute(&mut data));
+            Virtio::read_sector(sector as _, mem::transmute(&mut *data));
         }
 
         if data.inner.magic != INODE_MAGIC {
@@ -229,12 +239,11 @@ impl Vnode for Inode {
             if (chunk_size == SECTOR_SIZE) && (page_off <= PG_SIZE - SECTOR_SIZE) {
                 // Virtio only supports kernel buffers.
                 // So we need to convert the possible user buffer into kernel buffer.
-                let buf_kvm: &mut [u8; SECTOR_SIZE] = (&mut buf
-                    [bytes_read..bytes_read + SECTOR_SIZE])
+                // The user frame stays pinned until the transfer is done.
+                let mut pinned = (&mut buf[bytes_read..bytes_read + SECTOR_SIZE])
                     .translate()
-                    .ok_or(OsError::BadPtr)?
-                    .try_into()
-                    .unwrap();
+                    .ok_or(OsError::BadPtr)?;
+                let buf_kvm: &mut [u8; SECTOR_SIZE] = (&mut **pinned).try_into().unwrap();
                 Virtio::read_sector(sector as _, buf_kvm);
             } else {
                 // We need a bounce buffer.
@@ -292,12 +301,11 @@ impl Vnode for Inode {
             if (chunk_size == SECTOR_SIZE) && (page_off <= PG_SIZE - SECTOR_SIZE) {
                 // Virtio only supports kernel buffers.
                 // So we need to convert the possible user buffer into kernel buffer.
-                let buf_kvm: &[u8; SECTOR_SIZE] = (&buf
-                    [bytes_written..bytes_written + SECTOR_SIZE])
+                // The user frame stays pinned until the transfer is done.
+                let pinned = (&buf[bytes_written..bytes_written + SECTOR_SIZE])
                     .translate()
-                    .ok_or(OsError::BadPtr)?
-                    .try_into()
-                    .unwrap();
+                    .ok_or(OsError::BadPtr)?;
+                let buf_kvm: &[u8; SECTOR_SIZE] = (*pinned).try_into().unwrap();
                 Virtio::write_sector(sector as _, buf_kvm);
             } else {
                 // We need a bounce buffer, preserving old bytes which should not be written.
@@ -0,0 +1,174 @@
+//! Pipes.
+//!
+//! A pipe is a bounded buffer with a read end and a write end, each of them a
+//! [`Vnode`] opened as a [`File`]. Reads block while the pipe is empty, and
+//! writes block while it's full. Once every file of the write end is gone,
+//! reads drain the buffer and then report end of file with `0`. Once every
+//! file of the read end is gone, writes fail with [`OsError::BrokenPipe`].
+//!
+//! Offsets are meaningless to a pipe and ignored.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::cmp::min;
+
+use crate::fs::{File, Vnode};
+use crate::sync::{Condvar, Mutex};
+use crate::{OsError, Result};
+
+/// The most bytes a pipe holds before writers block
+pub const PIPE_SIZE: usize = 4096;
+
+struct State {
+    buf: VecDeque<u8>,
+    reader: bool,
+    writer: bool,
+}
+
+struct Pipe {
+    state: Mutex<State>,
+    /// Signaled when bytes are written, or the write end is closed
+    readable: Condvar,
+    /// Signaled when bytes are read, or the read end is closed
+    writable: Condvar,
+}
+
+/// Creates a pipe, and returns its read end and its write end.
+pub fn pipe() -> (File, File) {
+    let pipe = Arc::new(Pipe {
+        state: Mutex::new(State {
+            buf: VecDeque::with_capacity(PIPE_SIZE),
+            reader: true,
+            writer: true,
+        }),
+        readable: Condvar::new(),
+        writable: Condvar::new(),
+    });
+
+    (
+        File::new(Arc::new(Reader(pipe.clone()))),
+        File::new(Arc::new(Writer(pipe))),
+    )
+}
+
+struct Reader(Arc<Pipe>);
+
+struct Writer(Arc<Pipe>);
+
+impl Vnode for Reader {
+    /// Reads the bytes available, waiting for some if the pipe is empty.
+    fn read_at(&self, buf: &mut [u8], _off: usize) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut state = self.0.state.lock();
+        while state.buf.is_empty() && state.writer {
+            self.0.readable.wait(&mut state);
+        }
+
+        let len = min(buf.len(), state.buf.len());
+        for (dst, src) in buf.iter_mut().zip(state.buf.drain(..len)) {
+            *dst = src;
+        }
+        self.0.writable.notify_all();
+
+        Ok(len)
+    }
+
+    fn write_at(&self, _buf: &[u8], _off: usize) -> Result<usize> {
+        Err(OsError::InvalidFileMode)
+    }
+
+    fn deny_write(&self) {}
+
+    fn allow_write(&self) {}
+
+    fn inum(&self) -> usize {
+        Arc::as_ptr(&self.0) as usize
+    }
+
+    fn len(&self) -> usize {
+        self.0.state.lock().buf.len()
+    }
+
+    fn resize(&self, _size: usize) -> Result<()> {
+        Err(OsError::InvalidFileMode)
+    }
+
+    fn close(&self) {}
+
+    fn is_regular(&self) -> bool {
+        false
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock();
+        state.reader = false;
+        self.0.writable.notify_all();
+    }
+}
+
+impl Vnode for Writer {
+    fn read_at(&self, _buf: &mut [u8], _off: usize) -> Result<usize> {
+        Err(OsError::InvalidFileMode)
+    }
+
+    /// Writes all of `buf`, waiting for room whenever the pipe is full. If the
+    /// read end is closed halfway, the bytes written so far are reported.
+    fn write_at(&self, buf: &[u8], _off: usize) -> Result<usize> {
+        let mut state = self.0.state.lock();
+        let mut written = 0;
+
+        while written < buf.len() {
+            while state.buf.len() == PIPE_SIZE && state.reader {
+                self.0.writable.wait(&mut state);
+            }
+            if !state.reader {
+                break;
+            }
+
+            let len = min(buf.len() - written, PIPE_SIZE - state.buf.len());
+            state.buf.extend(&buf[written..written + len]);
+            written += len;
+            self.0.readable.notify_all();
+        }
+
+        match written {
+            0 if !buf.is_empty() => Err(OsError::BrokenPipe),
+            written => Ok(written),
+        }
+    }
+
+    fn deny_write(&self) {}
+
+    fn allow_write(&self) {}
+
+    fn inum(&self) -> usize {
+        Arc::as_ptr(&self.0) as usize
+    }
+
+    fn len(&self) -> usize {
+        self.0.state.lock().buf.len()
+    }
+
+    fn resize(&self, _size: usize) -> Result<()> {
+        Err(OsError::InvalidFileMode)
+    }
+
+    fn close(&self) {}
+
+    fn is_regular(&self) -> bool {
+        false
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock();
+        state.writer = false;
+        self.0.readable.notify_all();
+    }
+}
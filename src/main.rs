@@ -26,22 +26,28 @@ mod test;
 
 pub use error::OsError;
 
-use core::{ptr, slice, str};
+use alloc::boxed::Box;
+use core::{ptr, slice};
 use fdt::{standard_nodes::MemoryRegion, Fdt};
 use riscv::register;
 
 use fs::{disk::DISKFS, FileSys};
-use mem::PhysAddr;
+use mem::{PageAlign, PhysAddr, Regions};
 
 extern "C" {
     fn sbss();
     fn ebss();
     fn ekernel();
     fn bootstack();
+    fn bootstack_top();
 }
 
 pub type Result<T> = core::result::Result<T, OsError>;
 
+/// How long to wait for the remaining threads before shutting down
+#[cfg(feature = "mem-trace")]
+const SHUTDOWN_TICKS: i64 = 5 * sbi::timer::TICKS_PER_SEC as i64;
+
 /// Initializes major components of our kernel
 ///
 /// Note: `extern "C"` ensures this function adhere to the C calling convention.
@@ -55,47 +61,75 @@ pub extern "C" fn main(hart_id: usize, dtb: usize) -> ! {
 
     // Parse the device tree.
     let devtree = unsafe { Fdt::from_ptr(dtb as *const u8).unwrap() };
-    // Get the start point and length of physical memory
-    let (pm_base, pm_len) = {
-        let memory = devtree.memory();
-        let mut regions = memory.regions();
-        let MemoryRegion {
-            starting_address,
-            size,
-        } = regions.next().expect("No memory info.");
-        assert_eq!(regions.next(), None, "Unknown memory region");
-        (
-            starting_address as usize,
-            size.expect("Unknown physical memory length"),
-        )
-    };
-    assert_eq!(pm_base, mem::PM_BASE, "Error constant mem::PM_BASE.");
-    // Get the boot arguments.
-    let _bootargs: &'static str = unsafe {
-        let (vm, len) = {
-            let bootargs = devtree.chosen().bootargs().unwrap();
-            let len = bootargs.len();
-            (PhysAddr::from_pa(bootargs.as_ptr() as usize).into_va(), len)
-        };
-        str::from_utf8(slice::from_raw_parts(vm as *const u8, len)).unwrap()
-    };
+    let dtb_end = dtb + devtree.total_size();
+
+    // Collect the physical memory that is RAM and not reserved.
+    let mut ram = Regions::new();
+    for MemoryRegion {
+        starting_address,
+        size,
+    } in devtree.memory().regions()
+    {
+        let start = starting_address as usize;
+        ram.insert(start, start + size.expect("Unknown physical memory length"));
+    }
+    assert!(
+        ram.clip(mem::PM_BASE, mem::PM_BASE + 1).next().is_some(),
+        "Error constant mem::PM_BASE."
+    );
+    let ram_end = ram.end();
+
+    for reservation in devtree.memory_reservations() {
+        let start = reservation.address() as usize;
+        ram.remove(start, start + reservation.size());
+    }
+    for region in devtree
+        .find_node("/reserved-memory")
+        .into_iter()
+        .flat_map(|node| node.children())
+        .flat_map(|node| node.reg().into_iter().flatten())
+    {
+        let start = region.starting_address as usize;
+        ram.remove(start, start + region.size.unwrap_or(0));
+    }
+    // Firmware and the kernel image
+    ram.remove(mem::PM_BASE, ekernel as usize - mem::VM_OFFSET);
+
+    // The device tree stays in use until it's copied to the heap.
+    let mut free = ram.clone();
+    free.remove(dtb.floor(), dtb_end.ceil());
 
     // Initialize memory management.
-    let ram_base = ekernel as usize;
-    let ram_tail = dtb + mem::VM_OFFSET; // Current we do not reuse dtb area.
-    mem::init(ram_base, ram_tail, pm_len);
+    mem::init(&free, ram_end);
+
+    // Copy the device tree, and give its pages back.
+    let devtree: &'static [u8] = unsafe {
+        let copy =
+            slice::from_raw_parts(PhysAddr::from_pa(dtb).into_va() as *const u8, dtb_end - dtb);
+        Box::leak(Box::from(copy))
+    };
+    for (start, end) in ram.clip(dtb.floor(), dtb_end.ceil()) {
+        unsafe { mem::Palloc::insert_range(start + mem::VM_OFFSET, end + mem::VM_OFFSET) };
+    }
+    let devtree = Fdt::new(devtree).unwrap();
+
+    // Get the boot arguments.
+    let bootargs: &'static str = devtree.chosen().bootargs().unwrap();
 
     #[cfg(feature = "debug")]
     {
-        kprintln!("RAM: 0x{:x} - 0x{:x}", ram_base, ram_tail);
-        kprintln!("BOOTARGS: {:?}", _bootargs);
+        for (start, end) in free.iter() {
+            kprintln!("RAM: 0x{:x} - 0x{:x}", start, end);
+        }
+        kprintln!("BOOTARGS: {:?}", bootargs);
     }
 
     trap::set_strap_entry();
 
     unsafe {
         register::sstatus::set_sie();
-        register::sstatus::set_sum();
+        // User programs may read the time to measure themselves.
+        register::scounteren::set_tm();
     };
 
     device::plic::init(hart_id);
@@ -105,6 +139,16 @@ pub extern "C" fn main(hart_id: usize, dtb: usize) -> ! {
     // Init timer & external interrupt
     sbi::interrupt::init();
 
+    // Carry on in a new thread, so that the boot stack is given back to the
+    // page allocator after the initial thread exits.
+    thread::Builder::new(move || kernel_main(bootargs))
+        .name("main")
+        .spawn();
+    thread::exit()
+}
+
+/// Runs the kernel after initialization, and then shuts down
+fn kernel_main(_bootargs: &'static str) -> ! {
     #[cfg(feature = "test")]
     {
         use alloc::sync::Arc;
@@ -115,12 +159,26 @@ pub extern "C" fn main(hart_id: usize, dtb: usize) -> ! {
     }
 
     #[cfg(feature = "shell")]
+    shell();
+
+    // Allocations of threads still running would show up as leaks, so wait
+    // until only this thread and the idle one are left. A blocked thread may
+    // never leave, so give up after a while; the report lists those left.
+    #[cfg(feature = "mem-trace")]
     {
-        // TODO: Lab 0
+        let start = sbi::timer::timer_ticks();
+        while thread::Manager::get().alive() > 2
+            && sbi::timer::timer_elapsed(start) < SHUTDOWN_TICKS
+        {
+            thread::schedule();
+        }
     }
 
     DISKFS.unmount();
 
+    #[cfg(feature = "mem-trace")]
+    mem::trace::report();
+
     kprintln!("Goodbye, World!");
 
     sbi::reset(
@@ -129,6 +187,61 @@ pub extern "C" fn main(hart_id: usize, dtb: usize) -> ! {
     )
 }
 
+/// A debug shell on the console, which runs commands until `exit`
+#[cfg(feature = "shell")]
+fn shell() {
+    let mut buf = [0u8; 64];
+    loop {
+        kprint!("tacos> ");
+        let len = read_line(&mut buf);
+        let mut words = core::str::from_utf8(&buf[..len])
+            .unwrap_or("")
+            .split_whitespace();
+        match (words.next(), words.next()) {
+            (None, _) => {}
+            (Some("exit"), None) => break,
+            (Some("memmap"), Some(tid)) => match tid.parse() {
+                Ok(tid) => {
+                    if userproc::memmap(tid).is_err() {
+                        kprintln!("Thread {} doesn't run a user process.", tid);
+                    }
+                }
+                Err(_) => kprintln!("Usage: memmap <tid>"),
+            },
+            _ => kprintln!("Commands: memmap <tid>, exit"),
+        }
+    }
+}
+
+/// Reads a line from the console into `buf`, echoing it back.
+///
+/// ## Return
+/// The length of the line, which is cut at the end of `buf`.
+#[cfg(feature = "shell")]
+fn read_line(buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    loop {
+        match sbi::console_getchar() {
+            // Nothing was typed yet.
+            usize::MAX => thread::schedule(),
+            0x0a | 0x0d => {
+                kprintln!();
+                return len;
+            }
+            0x08 | 0x7f if len > 0 => {
+                len -= 1;
+                kprint!("\x08 \x08");
+            }
+            ch if len < buf.len() && (0x20..0x7f).contains(&ch) => {
+                buf[len] = ch as u8;
+                len += 1;
+                kprint!("{}", ch as u8 as char);
+            }
+            _ => {}
+        }
+    }
+}
+
 /* ---------------------------------- PANIC --------------------------------- */
 #[panic_handler]
 unsafe fn panic(info: &core::panic::PanicInfo) -> ! {
@@ -11,18 +11,24 @@ pub mod layout;
 pub mod malloc;
 pub mod pagetable;
 pub mod palloc;
+pub mod regions;
+pub mod slab;
+#[cfg(feature = "mem-trace")]
+pub mod trace;
 pub mod userbuf;
 mod utils;
 
 use core::mem::size_of;
+use core::ops::{Deref, DerefMut};
 
 pub use self::layout::*;
-pub use self::malloc::{kalloc, kfree};
+pub use self::malloc::{kalloc, kfree, try_kalloc};
 pub use self::pagetable::*;
 pub use self::palloc::Palloc;
+pub use self::regions::Regions;
 pub use self::utils::*;
 
-use self::palloc::USER_POOL_LIMIT;
+use self::palloc::UserPool;
 
 pub fn get_pte(va: usize) -> Option<Entry> {
     match crate::thread::Manager::get().current.lock().pagetable {
@@ -31,29 +37,83 @@ pub fn get_pte(va: usize) -> Option<Entry> {
     }
 }
 
-pub fn init(ram_base: usize, ram_tail: usize, pm_len: usize) {
-    let palloc_tail = ram_tail - USER_POOL_LIMIT * PG_SIZE;
+/// Initializes memory management with `free`, the physical memory that nothing
+/// else uses. RAM ends at physical address `ram_end`.
+pub fn init(free: &Regions, ram_end: usize) {
+    extern "C" {
+        fn entry_pgtable();
+    }
+
+    // Palloc keeps its free lists in free memory, but only the first GB of RAM
+    // is mapped by the entry page table. The rest is handed over after the
+    // kernel page table is activated.
+    let boot_mapped = PM_BASE + (1 << 30);
 
     unsafe {
-        palloc::Palloc::init(ram_base, palloc_tail);
-        palloc::UserPool::init(palloc_tail, ram_tail);
-        KernelPgTable::init(pm_len);
+        for (start, end) in free.clip(PM_BASE, boot_mapped) {
+            Palloc::insert_range(start + VM_OFFSET, end + VM_OFFSET);
+        }
+
+        KernelPgTable::init(ram_end - PM_BASE);
+
+        for (start, end) in free.clip(boot_mapped, usize::MAX) {
+            Palloc::insert_range(start + VM_OFFSET, end + VM_OFFSET);
+        }
+
+        // Nothing runs on the entry page table anymore.
+        let entry_pgtable = entry_pgtable as *const () as usize;
+        Palloc::insert_range(entry_pgtable, entry_pgtable + PG_SIZE);
+
+        UserPool::init(ram_end);
     }
 }
 
 /// Translate a virtual address (pointer, slice) to a kernel virtual address
 /// if it's in user space. The translated user object is supposed to be in a page.
+///
+/// The frame of a user object is pinned until the returned [`Pinned`] is
+/// dropped, so it isn't evicted while the kernel accesses it.
 pub trait Translate: Sized {
-    fn translate(self) -> Option<Self>;
+    fn translate(self) -> Option<Pinned<Self>>;
+}
+
+/// A translated object, whose user frame stays allocated as long as it lives
+pub struct Pinned<T> {
+    value: T,
+    frame: Option<*mut u8>,
+}
+
+impl<T> Deref for Pinned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for Pinned<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T> Drop for Pinned<T> {
+    fn drop(&mut self) {
+        if let Some(frame) = self.frame {
+            unsafe { UserPool::dealloc_pages(frame, 1) };
+        }
+    }
 }
 
 fn in_same_page(va1: usize, va2: usize) -> bool {
     va1 / PG_SIZE == va2 / PG_SIZE
 }
 
-fn translate(va: usize, len: usize) -> Option<usize> {
+/// ## Return
+/// The kernel virtual address, and the user frame pinned for it.
+fn translate(va: usize, len: usize, write: bool) -> Option<(usize, Option<*mut u8>)> {
     if in_kernel_space(va) {
-        return Some(va);
+        return Some((va, None));
     }
 
     if !in_same_page(va, va + len - 1) {
@@ -61,38 +121,93 @@ fn translate(va: usize, len: usize) -> Option<usize> {
     }
 
     let pageoff = va & 0xFFF;
-    let pte = get_pte(va)?;
-    Some(pte.pa().into_va() | pageoff)
+    // Nothing evicts pages of a kernel thread, nor does it write to them.
+    if crate::thread::current().pagetable.is_none() {
+        let pte = get_pte(va).filter(Entry::is_valid)?;
+        return match write {
+            true => None,
+            false => Some((pte.pa().into_va() | pageoff, None)),
+        };
+    }
+
+    // The page may be evicted again before it's pinned.
+    loop {
+        // The page may not have been brought in yet.
+        if get_pte(va).filter(Entry::is_valid).is_none() && !crate::userproc::load_page(va, write) {
+            return None;
+        }
+        if write {
+            prepare_user_write(va)?;
+        }
+        if let Some(frame) = pin(va) {
+            return Some((frame as usize | pageoff, Some(frame)));
+        }
+    }
+}
+
+/// Kernel writes through the translated address of a user page bypass the
+/// MMU. Like a store from user mode would, such a write has to break the
+/// sharing of a copy-on-write page, and mark the page as dirty. It fails on a
+/// page the user can't write, which the MMU would fault on.
+fn prepare_user_write(va: usize) -> Option<Entry> {
+    crate::userproc::copy_on_write(va);
+
+    let current = crate::thread::current();
+    let mut pagetable = current.pagetable.as_ref()?.lock();
+    let pte = pagetable
+        .get_pte_mut(va)
+        .filter(|pte| pte.is_valid() && pte.flag().contains(PTEFlags::W | PTEFlags::U))?;
+    pte.set_flag(pte.flag() | PTEFlags::A | PTEFlags::D);
+    Some(*pte)
+}
+
+/// Adds an owner to the frame mapped at `va`, which keeps it from being
+/// evicted. The page table lock keeps eviction out meanwhile.
+fn pin(va: usize) -> Option<*mut u8> {
+    let current = crate::thread::current();
+    let pagetable = current.pagetable.as_ref()?.lock();
+    let pte = pagetable.get_pte(va).filter(|pte| pte.is_valid())?;
+    let frame = pte.pa().into_va() as *mut u8;
+    unsafe { UserPool::share_page(frame) };
+    Some(frame)
 }
 
 impl<T> Translate for *const T {
-    fn translate(self) -> Option<Self> {
-        translate(self as usize, size_of::<T>()).map(|va| va as *const T)
+    fn translate(self) -> Option<Pinned<Self>> {
+        translate(self as usize, size_of::<T>(), false).map(|(va, frame)| Pinned {
+            value: va as *const T,
+            frame,
+        })
     }
 }
 
 impl<T> Translate for *mut T {
-    fn translate(self) -> Option<Self> {
-        translate(self as usize, size_of::<T>()).map(|va| va as *mut T)
+    fn translate(self) -> Option<Pinned<Self>> {
+        translate(self as usize, size_of::<T>(), true).map(|(va, frame)| Pinned {
+            value: va as *mut T,
+            frame,
+        })
     }
 }
 
 impl<T> Translate for &[T] {
-    fn translate(self) -> Option<Self> {
+    fn translate(self) -> Option<Pinned<Self>> {
         let ptr = self.as_ptr();
         let len = self.len();
-        translate(ptr as usize, len * size_of::<T>())
-            .map(|va| va as *const T)
-            .map(|ptr| unsafe { core::slice::from_raw_parts(ptr, len) })
+        translate(ptr as usize, len * size_of::<T>(), false).map(|(va, frame)| Pinned {
+            value: unsafe { core::slice::from_raw_parts(va as *const T, len) },
+            frame,
+        })
     }
 }
 
 impl<T> Translate for &mut [T] {
-    fn translate(self) -> Option<Self> {
+    fn translate(self) -> Option<Pinned<Self>> {
         let ptr = self.as_mut_ptr();
         let len = self.len();
-        translate(ptr as usize, len * size_of::<T>())
-            .map(|va| va as *mut T)
-            .map(|ptr| unsafe { core::slice::from_raw_parts_mut(ptr, len) })
+        translate(ptr as usize, len * size_of::<T>(), true).map(|(va, frame)| Pinned {
+            value: unsafe { core::slice::from_raw_parts_mut(va as *mut T, len) },
+            frame,
+        })
     }
 }
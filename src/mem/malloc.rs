@@ -1,18 +1,44 @@
 //! Kernel memory allocator
 
+#[cfg(feature = "mem-poison")]
+pub mod poison;
+
 use core::alloc::{GlobalAlloc, Layout};
 use core::cmp::max;
+use core::fmt;
 use core::mem::size_of;
-use core::ptr::NonNull;
+use core::panic::Location;
+use core::ptr::{self, NonNull};
 use core::sync::atomic::{AtomicUsize, Ordering::Relaxed};
 
 use crate::mem::palloc::Palloc;
+#[cfg(feature = "mem-trace")]
+use crate::mem::trace;
 use crate::mem::utils::*;
 use crate::sync::{Intr, Lazy, Mutex};
+use crate::Result;
 
 const ARENA_MAGIC: u32 = 0x9a548eed;
 const MAX_BLKSIZE: usize = PG_SIZE / 4;
 
+/// Where an allocation comes from
+#[derive(Clone, Copy)]
+pub enum Site {
+    /// Call site of `kalloc`
+    Caller(&'static Location<'static>),
+    /// Subsystem tag of a `GlobalAlloc` allocation
+    Tag(&'static str),
+}
+
+impl fmt::Display for Site {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Site::Caller(location) => write!(f, "{}", location),
+            Site::Tag(tag) => write!(f, "[{}]", tag),
+        }
+    }
+}
+
 /// Metadata for a page of memory
 ///
 /// An arena can hold memory blocks of sizes that are up to [`MAX_BLKSIZE`]
@@ -24,6 +50,9 @@ struct Arena {
     free_cnt: u32,
     /// Points to an arena's corresponding descriptor
     desc: *const Desc,
+    /// The next arena of the same descriptor
+    #[cfg(feature = "mem-poison")]
+    next: *mut Arena,
 }
 
 impl Arena {
@@ -64,6 +93,11 @@ struct Desc {
     allocated: usize,
     free: usize,
     total: usize,
+    /// The number of allocations served so far
+    allocs: usize,
+    /// All arenas of this descriptor
+    #[cfg(feature = "mem-poison")]
+    arenas: *mut Arena,
 }
 
 impl Desc {
@@ -76,19 +110,29 @@ impl Desc {
             allocated: 0,
             free: 0,
             total: 0,
+            allocs: 0,
+            #[cfg(feature = "mem-poison")]
+            arenas: core::ptr::null_mut(),
         }
     }
 
     /// Allocates a free memory block from this descriptor
-    unsafe fn alloc(&mut self) -> *mut u8 {
+    unsafe fn alloc(&mut self) -> Result<*mut u8> {
         if self.free_list.is_empty() {
-            let mut arena: NonNull<Arena> = NonNull::new_unchecked(Palloc::alloc(1)).cast();
+            let mut arena: NonNull<Arena> = NonNull::new_unchecked(Palloc::try_alloc(1)?).cast();
             arena.as_mut().magic = ARENA_MAGIC;
             arena.as_mut().desc = self as *const Self;
             arena.as_mut().free_cnt = self.blocks_per_arena as u32;
+            #[cfg(feature = "mem-poison")]
+            {
+                arena.as_mut().next = self.arenas;
+                self.arenas = arena.as_ptr();
+            }
 
             for i in 0..self.blocks_per_arena {
                 let block = arena.as_ref().get_block(i);
+                #[cfg(feature = "mem-poison")]
+                poison::init(block as *mut u8, self.block_size);
                 self.free_list.push(block as *mut _);
             }
 
@@ -98,12 +142,13 @@ impl Desc {
 
         self.allocated += self.block_size;
         self.free -= self.block_size;
+        self.allocs += 1;
 
         let block = self.free_list.pop().unwrap() as *mut u8;
         let arena = Arena::from_block(block);
         arena.free_cnt -= 1;
 
-        block
+        Ok(block)
     }
 
     /// Returns a memory block back to this descriptor
@@ -117,6 +162,18 @@ impl Desc {
         let arena = Arena::from_block(ptr);
         arena.free_cnt += 1;
     }
+
+    /// Checks every block of this descriptor
+    #[cfg(feature = "mem-poison")]
+    unsafe fn check(&self) {
+        let mut arena = self.arenas;
+        while let Some(a) = arena.as_ref() {
+            for i in 0..self.blocks_per_arena {
+                poison::check(a.get_block(i) as *mut u8, self.block_size);
+            }
+            arena = a.next;
+        }
+    }
 }
 
 /// An elastic kernel heap. It's a memory allocator more fine-grained than [`Palloc`].
@@ -147,42 +204,67 @@ impl Heap {
     }
 
     /// Allocates a memory block that is in align with the layout.
+    ///
+    /// Returns null if memory is exhausted.
+    ///
+    /// ## Safety
+    /// The block is uninitialized, and must be given back with
+    /// [`Heap::dealloc`] with the same layout.
     pub unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_by(layout, None).unwrap_or(ptr::null_mut())
+    }
+
+    /// Allocates a memory block for `owner`, which is only recorded in
+    /// debugging modes.
+    ///
+    /// ## Errors
+    /// [`OutOfMemory`](crate::OsError::OutOfMemory) if no pages are left for the block.
+    #[cfg_attr(not(feature = "mem-poison"), allow(unused_variables))]
+    unsafe fn alloc_by(&self, layout: Layout, owner: Option<Site>) -> Result<*mut u8> {
         if layout.size() == 0 {
             // return an invalid but well-aligned pointer for zero-sized requests
-            return NonNull::dangling().as_ptr();
+            return Ok(NonNull::dangling().as_ptr());
         }
 
         assert!(layout.align().is_power_of_two());
         // not able to handle align requests that are larger than one page
         assert!(layout.align() <= PG_SIZE);
 
-        let size = max(layout.size().next_power_of_two(), layout.align());
+        let size = max(Self::block_size(layout), layout.align());
         if size <= MAX_BLKSIZE {
-            return self.descs[size.trailing_zeros().saturating_sub(3) as usize]
-                .lock()
-                .alloc();
+            // Redzones are set up under the lock, where heap checks can't interleave
+            let mut desc = self.descs[size.trailing_zeros().saturating_sub(3) as usize].lock();
+            let block = desc.alloc()?;
+            #[cfg(feature = "mem-poison")]
+            let block = poison::on_alloc(block, size, layout.size(), layout.align(), owner);
+            return Ok(block);
         }
 
         // delegate the allocation request to PALLOC
         let pages = (layout.size() + PG_SIZE - 1) / PG_SIZE;
+        let ptr = Palloc::try_alloc(pages)?;
         self.spilled_alloc.fetch_add(size, Relaxed);
         self.spilled_total.fetch_add(pages, Relaxed);
 
-        Palloc::alloc(pages)
+        Ok(ptr)
     }
 
     /// Deallocates a memory block
+    ///
+    /// ## Safety
+    /// `ptr` must come from [`Heap::alloc`] with `layout`, and must not be used
+    /// afterwards.
     pub unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         if ptr.is_null() {
             return;
         }
 
-        let size = max(layout.size().next_power_of_two(), layout.align());
+        let size = max(Self::block_size(layout), layout.align());
         if size <= MAX_BLKSIZE {
-            return self.descs[size.trailing_zeros().saturating_sub(3) as usize]
-                .lock()
-                .dealloc(ptr);
+            let mut desc = self.descs[size.trailing_zeros().saturating_sub(3) as usize].lock();
+            #[cfg(feature = "mem-poison")]
+            let ptr = poison::on_free(ptr, size, layout.align());
+            return desc.dealloc(ptr);
         }
 
         // delegate the deallocation request to PALLOC
@@ -194,6 +276,35 @@ impl Heap {
         Palloc::dealloc(ptr, pages);
     }
 
+    /// The smallest block that serves `layout`, ignoring its alignment
+    fn block_size(layout: Layout) -> usize {
+        #[cfg(feature = "mem-poison")]
+        let size = poison::padded(layout.size(), layout.align());
+        #[cfg(not(feature = "mem-poison"))]
+        let size = layout.size();
+        size.next_power_of_two()
+    }
+
+    /// Checks redzones and poison of all blocks, panicking on corruption
+    #[cfg(feature = "mem-poison")]
+    pub fn check(&self) {
+        self.descs
+            .iter()
+            .for_each(|desc| unsafe { desc.lock().check() });
+    }
+
+    /// Counters of each size class, as `(block_size, live_blocks, allocs)`
+    pub fn classes(&self) -> impl Iterator<Item = (usize, usize, usize)> + '_ {
+        self.descs.iter().map(|desc| {
+            let desc = desc.lock();
+            (
+                desc.block_size,
+                desc.allocated / desc.block_size,
+                desc.allocs,
+            )
+        })
+    }
+
     /// The amount of free memory in heap
     pub fn free(&self) -> usize {
         self.descs.iter().fold(0, |free, desc| {
@@ -226,16 +337,45 @@ pub struct Malloc;
 
 unsafe impl GlobalAlloc for Malloc {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        Heap::get().alloc(layout)
+        // Allocations through here are owned by the tag of the running thread.
+        #[cfg(feature = "mem-trace")]
+        let owner = Some(Site::Tag(trace::current_tag()));
+        #[cfg(not(feature = "mem-trace"))]
+        let owner = None;
+
+        let ptr = Heap::get()
+            .alloc_by(layout, owner)
+            .unwrap_or(ptr::null_mut());
+        #[cfg(feature = "mem-trace")]
+        if let (false, Some(owner)) = (ptr.is_null(), owner) {
+            trace::record(ptr, layout.size(), owner);
+        }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "mem-trace")]
+        trace::forget(ptr);
         Heap::get().dealloc(ptr, layout)
     }
 }
 
+/// Allocates `size` bytes aligned to `align`, panicking if memory is exhausted.
+#[track_caller]
 pub fn kalloc(size: usize, align: usize) -> *mut u8 {
-    unsafe { MALLOC.alloc(Layout::from_size_align(size, align).unwrap()) }
+    try_kalloc(size, align).expect("kernel heap is exhausted")
+}
+
+/// Like [`kalloc`], but fails with [`OutOfMemory`](crate::OsError::OutOfMemory) instead of
+/// panicking.
+#[track_caller]
+pub fn try_kalloc(size: usize, align: usize) -> Result<*mut u8> {
+    let layout = Layout::from_size_align(size, align).unwrap();
+    let owner = Site::Caller(Location::caller());
+    let ptr = unsafe { Heap::get().alloc_by(layout, Some(owner))? };
+    #[cfg(feature = "mem-trace")]
+    trace::record(ptr, size, owner);
+    Ok(ptr)
 }
 
 pub fn kfree(ptr: *mut u8, size: usize, align: usize) {
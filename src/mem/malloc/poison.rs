@@ -0,0 +1,177 @@
+//! Redzones and poisoning of heap blocks
+//!
+//! With feature `mem-poison`, a block handed out by a [`Desc`](super::Desc)
+//! is laid out as
+//!
+//! ```text
+//! | Header | redzone | object | redzone |
+//! ```
+//!
+//! Both redzones are filled with [`REDZONE`], and a freed block is filled with
+//! [`POISON`]. Blocks are checked when they are allocated, when they are freed,
+//! and every [`CHECK_INTERVAL`] timer ticks. Any corruption panics with the
+//! block, its size class and the owner that last allocated it.
+
+use core::cmp::max;
+use core::fmt;
+use core::mem::size_of;
+use core::slice;
+
+use super::Site;
+
+pub const REDZONE: u8 = 0xbb;
+pub const POISON: u8 = 0x6b;
+/// Timer ticks between two checks of the whole heap
+pub const CHECK_INTERVAL: i64 = 10;
+
+const LIVE: u16 = 0x11fe;
+const FREE: u16 = 0xf4ee;
+/// Smallest distance from a block to its object, header included. It's a
+/// power of two, so that objects stay aligned.
+const MIN_FRONT: usize = 64;
+/// Size of the redzone after an object
+const TAIL: usize = 16;
+
+/// Metadata at the beginning of every block
+#[repr(C)]
+struct Header {
+    /// Link of the descriptor's free list while the block is free
+    link: usize,
+    state: u16,
+    /// Distance from the block to the object
+    front: u16,
+    /// Requested size of the object
+    size: u32,
+    /// Who last allocated the block, if known
+    owner: Option<Site>,
+}
+
+const _: () = assert!(size_of::<Header>() <= MIN_FRONT);
+
+struct Owner(Option<Site>);
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(site) => write!(f, "{}", site),
+            None => write!(f, "unknown"),
+        }
+    }
+}
+
+fn front(align: usize) -> usize {
+    max(MIN_FRONT, align)
+}
+
+/// Size of a block that holds an object of `size` bytes with its redzones
+pub fn padded(size: usize, align: usize) -> usize {
+    front(align) + size + TAIL
+}
+
+unsafe fn bytes(block: *mut u8, from: usize, to: usize) -> &'static mut [u8] {
+    slice::from_raw_parts_mut(block.add(from), to - from)
+}
+
+unsafe fn report(kind: &str, block: *mut u8, block_size: usize) -> ! {
+    let header = &*(block as *const Header);
+    panic!(
+        "heap corruption: {} in block {:p}, size class {}, size {}, owner {}",
+        kind,
+        block,
+        block_size,
+        header.size,
+        Owner(header.owner)
+    )
+}
+
+/// Prepares a block of a new arena.
+///
+/// ## Safety
+/// `block` must point to `block_size` writable bytes owned by the caller.
+pub unsafe fn init(block: *mut u8, block_size: usize) {
+    let header = &mut *(block as *mut Header);
+    header.state = FREE;
+    header.front = 0;
+    header.size = 0;
+    header.owner = None;
+    bytes(block, size_of::<Header>(), block_size).fill(POISON);
+}
+
+/// Checks a block taken from a free list and returns the object in it.
+///
+/// ## Safety
+/// `block` must be a block of `block_size` bytes prepared by [`init`], and
+/// must fit an object of `size` and `align`.
+pub unsafe fn on_alloc(
+    block: *mut u8,
+    block_size: usize,
+    size: usize,
+    align: usize,
+    owner: Option<Site>,
+) -> *mut u8 {
+    check(block, block_size);
+    if (*(block as *const Header)).state != FREE {
+        report("allocation of a live block", block, block_size);
+    }
+
+    let front = front(align);
+    let header = &mut *(block as *mut Header);
+    header.state = LIVE;
+    header.front = front as u16;
+    header.size = size as u32;
+    header.owner = owner;
+
+    bytes(block, size_of::<Header>(), front).fill(REDZONE);
+    bytes(block, front + size, block_size).fill(REDZONE);
+
+    block.add(front)
+}
+
+/// Checks the block of a freed object, poisons it and returns the block.
+///
+/// ## Safety
+/// `obj` must come from [`on_alloc`] with the same `block_size` and `align`.
+pub unsafe fn on_free(obj: *mut u8, block_size: usize, align: usize) -> *mut u8 {
+    let block = obj.sub(front(align));
+    check(block, block_size);
+    if (*(block as *const Header)).state != LIVE {
+        report("double free", block, block_size);
+    }
+
+    (*(block as *mut Header)).state = FREE;
+    bytes(block, size_of::<Header>(), block_size).fill(POISON);
+
+    block
+}
+
+/// Checks the redzones of a live block, or the poison of a free one.
+///
+/// ## Safety
+/// `block` must be a block of `block_size` bytes prepared by [`init`].
+pub unsafe fn check(block: *mut u8, block_size: usize) {
+    let header = &*(block as *const Header);
+    match header.state {
+        LIVE => {
+            let front = header.front as usize;
+            let end = front + header.size as usize;
+            if bytes(block, size_of::<Header>(), front)
+                .iter()
+                .any(|&b| b != REDZONE)
+            {
+                report("underflow", block, block_size);
+            }
+            if bytes(block, end, block_size).iter().any(|&b| b != REDZONE) {
+                report("overflow", block, block_size);
+            }
+        }
+        FREE => {
+            if bytes(block, size_of::<Header>(), block_size)
+                .iter()
+                .any(|&b| b != POISON)
+            {
+                report("use after free", block, block_size);
+            }
+        }
+        _ => report("broken header", block, block_size),
+    }
+}
@@ -18,7 +18,11 @@
 //! This method replicates the kernel page table as a template for all user page tables.
 //! Having kernel pages existing in all user memory spaces, there will be no need to
 //! switch page table when doing a system call.
+//!
+//! User page tables are tagged with ASIDs (see [`asid`]), so switching between
+//! them doesn't flush the TLB.
 
+mod asid;
 mod entry;
 
 use core::ptr;
@@ -26,21 +30,32 @@ use core::{arch::asm, mem::transmute};
 
 use crate::mem::{
     layout::{MMIO_BASE, PLIC_BASE, VM_BASE},
-    malloc::{kalloc, kfree},
     palloc::UserPool,
+    slab::Cache,
     utils::{PageAlign, PhysAddr, PG_SIZE},
 };
 use crate::mem::{KERN_BASE, PG_SHIFT, VM_OFFSET};
-use crate::sync::OnceCell;
+use crate::sync::{Lazy, OnceCell};
+use crate::Result;
 
 pub use self::entry::*;
 
+use self::asid::Asid;
+
 const PPN_MASK: usize = (1 << 44) - 1;
 
+/// The page a page table lives in
+#[repr(C, align(4096))]
+struct TablePage([Entry; PageTable::NENTRY]);
+
+/// Pages of all page tables but the one set up at boot
+static TABLES: Lazy<Cache<TablePage>> = Lazy::new(|| Cache::new("page table"));
+
 /// Reference to a in-memory page table
 pub struct PageTable {
     /// Each page table has 512 entries.
     entries: &'static mut [Entry; Self::NENTRY],
+    asid: Asid,
 }
 
 impl PageTable {
@@ -52,42 +67,361 @@ impl PageTable {
     pub fn activate(&self) {
         // SATP layout: MODE(WARL) 4 bit | ASID(WARL) 16 bits | PPN(WARL) 44 bits
         let satp: usize = PhysAddr::from(self.entries.as_ptr()).ppn() | Self::SV39_MODE;
-        unsafe {
-            asm!(
-                "sfence.vma zero, zero",
-                "csrw satp, {satp}",
-                "sfence.vma zero, zero",
-                satp = in(reg) satp
-            );
+        match self.asid.get() {
+            // Entries of other address spaces are told apart by their ASIDs.
+            Some(asid) => unsafe {
+                asm!("csrw satp, {satp}", satp = in(reg) satp | asid << asid::SATP_SHIFT)
+            },
+            None => unsafe {
+                asm!(
+                    "sfence.vma zero, zero",
+                    "csrw satp, {satp}",
+                    "sfence.vma zero, zero",
+                    satp = in(reg) satp
+                );
+            },
+        }
+    }
+
+    /// Flushes the cached translation of `va` in this address space.
+    pub fn flush_tlb(&self, va: usize) {
+        match self.asid.current() {
+            Some(asid) if asid != 0 => unsafe {
+                asm!("sfence.vma {va}, {asid}", va = in(reg) va, asid = in(reg) asid)
+            },
+            _ => unsafe { asm!("sfence.vma {va}, zero", va = in(reg) va) },
+        }
+    }
+
+    /// Flushes all cached translations of this address space.
+    pub fn flush_asid(&self) {
+        match self.asid.current() {
+            Some(asid) if asid != 0 => unsafe {
+                asm!("sfence.vma zero, {asid}", asid = in(reg) asid)
+            },
+            _ => unsafe { asm!("sfence.vma zero, zero") },
         }
     }
 
     /// Maps `pa` to `va` and allocates page table when necessary.
+    ///
+    /// Megapages (2 MiB) and gigapages (1 GiB) are used wherever both addresses
+    /// are aligned to them, and no page table exists in their place yet.
+    ///
+    /// Panics if no memory is left for a page table, see [`PageTable::try_map`].
     pub fn map(&mut self, pa: PhysAddr, va: usize, size: usize, flag: PTEFlags) {
+        self.try_map(pa, va, size, flag)
+            .expect("no memory for a page table");
+    }
+
+    /// Like [`PageTable::map`], but fails with [`OutOfMemory`](crate::OsError::OutOfMemory) if a
+    /// page table can't be allocated. Pages mapped so far stay mapped.
+    pub fn try_map(&mut self, pa: PhysAddr, va: usize, size: usize, flag: PTEFlags) -> Result<()> {
+        self.map_range(pa, va, size, flag, 2)
+    }
+
+    /// Maps `pa` to `va` like [`PageTable::map`], but with 4 KiB pages only.
+    pub fn map_pages(&mut self, pa: PhysAddr, va: usize, size: usize, flag: PTEFlags) {
+        self.map_range(pa, va, size, flag, 0)
+            .expect("no memory for a page table");
+    }
+
+    /// Maps with leaves of at most `max_level`, see [`PageTable::leaf`].
+    fn map_range(
+        &mut self,
+        pa: PhysAddr,
+        va: usize,
+        size: usize,
+        flag: PTEFlags,
+        max_level: u32,
+    ) -> Result<()> {
         assert!(pa.is_aligned() && va.is_aligned(), "address misaligns");
 
         let pa_end = pa.value() + size;
         let (mut pa, mut va) = (pa.value(), va);
 
         while pa < pa_end {
-            let mut l1_table = self.walk_or_create(Self::px(2, va), flag.contains(PTEFlags::G));
-            let l0_table = l1_table.walk_or_create(Self::px(1, va), flag.contains(PTEFlags::G));
-            l0_table.entries[Self::px(0, va)] = Entry::new(PhysAddr::from_pa(pa), flag);
-            pa += PG_SIZE;
-            va += PG_SIZE;
+            let mapped = self.map_leaf(2, pa, va, pa_end - pa, flag, max_level)?;
+            pa += mapped;
+            va += mapped;
+        }
+        Ok(())
+    }
+
+    /// Maps a leaf as large as allowed at `va`, and returns its size.
+    fn map_leaf(
+        &mut self,
+        level: u32,
+        pa: usize,
+        va: usize,
+        remaining: usize,
+        flag: PTEFlags,
+        max_level: u32,
+    ) -> Result<usize> {
+        let index = Self::px(level, va);
+        let size = PG_SIZE << (9 * level);
+        let entry = self.entries[index];
+        let is_table = entry.is_valid() && !entry.is_leaf();
+
+        let fits = level <= max_level && pa % size == 0 && va % size == 0 && remaining >= size;
+        if level == 0 || (fits && !is_table) {
+            self.entries[index] = Entry::new(PhysAddr::from_pa(pa), flag);
+            return Ok(size);
+        }
+
+        assert!(
+            !entry.is_valid() || is_table,
+            "remapping part of a huge page"
+        );
+        self.walk_or_create(index, flag.contains(PTEFlags::G))?
+            .map_leaf(level - 1, pa, va, remaining, flag, max_level)
+    }
+
+    /// Finds the entry that translates `va`. It's either a leaf of the returned
+    /// level, which maps `1 << (9 * level)` pages, or an invalid entry of
+    /// level 0.
+    fn find(&self, va: usize) -> Option<(*mut Entry, u32)> {
+        let mut table = self.entries.as_ptr() as *mut Entry;
+        for level in (1..=2).rev() {
+            let entry = unsafe { table.add(Self::px(level, va)) };
+            let entry_ref = unsafe { &*entry };
+            if !entry_ref.is_valid() {
+                return None;
+            }
+            if entry_ref.is_leaf() {
+                return Some((entry, level));
+            }
+            table = entry_ref.pa().into_va() as *mut Entry;
+        }
+        Some((unsafe { table.add(Self::px(0, va)) }, 0))
+    }
+
+    /// Finds the valid leaf entry that maps `va`, and the number of bytes it
+    /// maps, which is a page, a megapage or a gigapage.
+    pub fn leaf(&self, va: usize) -> Option<(Entry, usize)> {
+        let (entry, level) = self.find(va)?;
+        let entry = unsafe { *entry };
+        match entry.is_valid() {
+            true => Some((entry, PG_SIZE << (9 * level))),
+            false => None,
         }
     }
 
     /// Finds the corresponding entry by the given virtual address
     pub fn get_pte(&self, va: usize) -> Option<&Entry> {
-        self.walk(Self::px(2, va)).and_then(|l1_table| {
-            l1_table
-                .walk(Self::px(1, va))
-                .map(|l0_table| l0_table.entries.get(Self::px(0, va)).unwrap())
-        })
+        self.find(va).map(|(entry, _)| unsafe { &*entry })
+    }
+
+    /// Finds the corresponding entry by the given virtual address, mutably
+    pub fn get_pte_mut(&mut self, va: usize) -> Option<&mut Entry> {
+        self.find(va).map(|(entry, _)| unsafe { &mut *entry })
+    }
+
+    /// Removes the mapping of `va`, and returns the entry if it was valid.
+    /// If `va` lies in a megapage or a gigapage, the whole of it is unmapped.
+    ///
+    /// The mapped frame isn't freed, it's up to the caller.
+    pub fn unmap(&mut self, va: usize) -> Option<Entry> {
+        let entry = self.get_pte_mut(va).filter(|entry| entry.is_valid())?;
+        let old = core::mem::replace(entry, Entry::new(PhysAddr::from_pa(0), PTEFlags::empty()));
+        self.flush_tlb(va);
+        Some(old)
+    }
+
+    /// Clones the user memory space of this page table into a new one, which
+    /// is also a template-based table (see [`KernelPgTable::clone`]).
+    ///
+    /// User frames are not copied. Both tables map them, and writable ones are
+    /// turned into read-only [`PTEFlags::COW`] pages in both tables, which get
+    /// copied by [`PageTable::copy_on_write`] on the first write. Pages for
+    /// which `shared` holds stay writable in both tables.
+    ///
+    /// ## Errors
+    /// [`OutOfMemory`](crate::OsError::OutOfMemory) if the child runs out of page tables. Pages
+    /// left copy-on-write in this table are taken back on the next write.
+    pub fn fork(&mut self, shared: impl Fn(usize) -> bool) -> Result<PageTable> {
+        let mut child = KernelPgTable::try_clone()?;
+        let mut downgraded = false;
+        let mut result = Ok(());
+
+        self.for_each_user_leaf(|va, entry| {
+            if result.is_err() {
+                return;
+            }
+            if entry.flag().contains(PTEFlags::W) && !shared(va) {
+                entry.set_flag((entry.flag() - PTEFlags::W) | PTEFlags::COW);
+                downgraded = true;
+            }
+
+            result = child.try_map(entry.pa(), va, PG_SIZE, entry.flag());
+            if result.is_ok() {
+                unsafe { UserPool::share_page(entry.pa().into_va() as *mut _) };
+            }
+        });
+
+        if downgraded {
+            self.flush_asid();
+        }
+
+        match result {
+            Ok(()) => Ok(child),
+            Err(e) => {
+                unsafe { child.destroy() };
+                Err(e)
+            }
+        }
+    }
+
+    /// Gives this page table a private and writable copy of the copy-on-write
+    /// page at `va`, using the free frame `copy`. The copy is skipped if no one
+    /// else shares the frame, and `copy` is freed if it's not used.
+    ///
+    /// ## Return
+    /// `false` if `va` isn't mapped to a copy-on-write page.
+    ///
+    /// ## Safety
+    /// `copy` must be a page from [`UserPool`] that the caller owns, which is
+    /// taken over by this function. No one else may write the shared frame
+    /// meanwhile.
+    pub unsafe fn copy_on_write(&mut self, va: usize, copy: *mut u8) -> bool {
+        let entry = match self.get_pte_mut(va) {
+            Some(entry) if entry.is_valid() && entry.is_cow() => entry,
+            _ => {
+                UserPool::dealloc_pages(copy, 1);
+                return false;
+            }
+        };
+
+        let flag = (entry.flag() - PTEFlags::COW) | PTEFlags::W;
+        let frame = entry.pa().into_va() as *mut u8;
+
+        if UserPool::page_refs(frame) == 1 {
+            entry.set_flag(flag);
+            UserPool::dealloc_pages(copy, 1);
+        } else {
+            ptr::copy_nonoverlapping(frame, copy, PG_SIZE);
+            *entry = Entry::new(PhysAddr::from(copy), flag);
+            UserPool::dealloc_pages(frame, 1);
+        }
+
+        self.flush_tlb(va);
+        true
+    }
+
+    /// Calls `f` on every valid user leaf entry with the virtual address it maps.
+    fn for_each_user_leaf(&mut self, mut f: impl FnMut(usize, &mut Entry)) {
+        fn for_each_imp(
+            pgt: &mut PageTable,
+            level: usize,
+            base: usize,
+            f: &mut impl FnMut(usize, &mut Entry),
+        ) {
+            pgt.entries
+                .iter_mut()
+                .enumerate()
+                .filter(|(_, entry)| entry.is_valid() && !entry.is_global())
+                .for_each(|(idx, entry)| {
+                    let va = base | idx << (PG_SHIFT + 9 * level);
+                    if entry.is_leaf() {
+                        f(va, entry);
+                    } else {
+                        let table = entry.pa().into_va() as *mut _;
+                        for_each_imp(&mut unsafe { PageTable::from_raw(table) }, level - 1, va, f);
+                    }
+                });
+        }
+        for_each_imp(self, 2, 0, &mut f);
+    }
+
+    /// Calls `f` on every valid leaf entry with the virtual address and the
+    /// number of bytes it maps, in the order of virtual addresses.
+    fn for_each_leaf(&self, mut f: impl FnMut(usize, usize, &Entry)) {
+        fn for_each_imp(
+            pgt: &PageTable,
+            level: usize,
+            base: usize,
+            f: &mut impl FnMut(usize, usize, &Entry),
+        ) {
+            pgt.entries
+                .iter()
+                .enumerate()
+                .filter(|(_, entry)| entry.is_valid())
+                .for_each(|(idx, entry)| {
+                    let shift = PG_SHIFT + 9 * level;
+                    let mut va = base | idx << shift;
+                    // Bits above 38 copy bit 38.
+                    if va & 1 << 38 != 0 {
+                        va |= !0 << 39;
+                    }
+                    if entry.is_leaf() {
+                        f(va, 1 << shift, entry);
+                    } else {
+                        let table = entry.pa().into_va() as *mut _;
+                        for_each_imp(&unsafe { PageTable::from_raw(table) }, level - 1, va, f);
+                    }
+                });
+        }
+        for_each_imp(self, 2, 0, &mut f);
+    }
+
+    /// Calls `f` with the start, end, physical address and flags of every
+    /// range of mappings in this table. Neighbouring pages are merged into one
+    /// range if they map consecutive frames with the same flags, where
+    /// [`PTEFlags::A`] and [`PTEFlags::D`] don't count.
+    pub fn for_each_range(&self, mut f: impl FnMut(usize, usize, usize, PTEFlags)) {
+        let mut range: Option<(usize, usize, usize, PTEFlags)> = None;
+        self.for_each_leaf(|va, size, entry| {
+            let flags = entry.flag() - PTEFlags::A - PTEFlags::D;
+            let pa = entry.pa().value();
+            range = match range {
+                Some((start, end, base, fl))
+                    if end == va && base + (end - start) == pa && fl == flags =>
+                {
+                    Some((start, va + size, base, fl))
+                }
+                _ => {
+                    if let Some((start, end, base, fl)) = range {
+                        f(start, end, base, fl);
+                    }
+                    Some((va, va + size, pa, flags))
+                }
+            };
+        });
+        if let Some((start, end, base, flags)) = range {
+            f(start, end, base, flags);
+        }
+    }
+
+    /// Prints all mappings of this table, see [`PageTable::for_each_range`].
+    pub fn dump(&self) {
+        self.for_each_range(|start, end, pa, flags| {
+            kprintln!("  {:#x}-{:#x} -> {:#x} {:?}", start, end, pa, flags);
+        });
+    }
+
+    /// The number of page-table pages owned by this table, which are the ones
+    /// [`PageTable::destroy`] frees.
+    pub fn tables(&self) -> usize {
+        fn tables_imp(pgt: &PageTable) -> usize {
+            1 + pgt
+                .entries
+                .iter()
+                .filter(|entry| entry.is_valid() && !entry.is_global() && !entry.is_leaf())
+                .map(|entry| {
+                    tables_imp(&unsafe { PageTable::from_raw(entry.pa().into_va() as *mut _) })
+                })
+                .sum::<usize>()
+        }
+        tables_imp(self)
     }
 
     /// Free all memory used by this pagetable back to where they were allocated.
+    ///
+    /// A megapage or gigapage leaf frees its whole frame.
+    ///
+    /// ## Safety
+    /// The page table must not be active on any hart, and must not be used
+    /// afterwards. Its user frames must not be in use by the kernel either.
     pub unsafe fn destroy(&mut self) {
         unsafe fn destroy_imp(pgt: &mut PageTable, level: usize) {
             assert!((0..=2).contains(&level));
@@ -103,32 +437,42 @@ impl PageTable {
                         destroy_imp(&mut PageTable::from_raw(va as *mut _), level - 1);
                     }
                 });
-            kfree(pgt.entries.as_mut_ptr().cast(), PG_SIZE, PG_SIZE);
+            TABLES.free(pgt.entries.as_mut_ptr().cast());
         }
         destroy_imp(self, 2);
     }
 
     /// Allocates a page to build a new page table
-    fn new() -> Self {
-        let page = kalloc(PG_SIZE, PG_SIZE);
+    fn new() -> Result<Self> {
+        let page = TABLES.alloc()?;
 
         unsafe {
             // Clear the pagetable. A page table is exactly the size of
             // a page and must always be aligned to a page boundary.
-            ptr::write_bytes(page, 0, PG_SIZE);
+            ptr::write_bytes(page, 0, 1);
 
-            Self::from_raw(page.cast())
+            Ok(Self::from_raw(page.cast()))
         }
     }
 
     /// Interprets a page of raw memory as a page table
+    ///
+    /// ## Safety
+    /// `entries` must point to a page table that lives as long as the result,
+    /// and is not accessed but through it while it's mutated.
     unsafe fn from_raw(entries: *mut Entry) -> Self {
         assert!((entries as usize).is_aligned());
         Self {
             entries: transmute(entries),
+            asid: Asid::new(),
         }
     }
 
+    /// The page table that `satp` points to
+    ///
+    /// ## Safety
+    /// The page table must stay alive while the result is used. The result
+    /// must not be destroyed unless it's the only reference to the table.
     pub unsafe fn effective_pagetable() -> Self {
         let satp: usize;
         asm!("csrr {v}, satp", v = out(reg) satp);
@@ -143,16 +487,18 @@ impl PageTable {
             .map(|e| unsafe { Self::from_raw(e.pa().into_va() as *mut _) })
     }
 
-    fn walk_or_create(&mut self, index: usize, is_global: bool) -> PageTable {
+    fn walk_or_create(&mut self, index: usize, is_global: bool) -> Result<PageTable> {
         let mut flag = PTEFlags::V;
         flag.set(PTEFlags::G, is_global);
 
-        self.walk(index).unwrap_or_else(|| {
-            let table = PageTable::new();
-            let pa = PhysAddr::from(table.entries.as_ptr());
-            self.entries[index] = Entry::new(pa, flag);
-            table
-        })
+        if let Some(table) = self.walk(index) {
+            return Ok(table);
+        }
+
+        let table = PageTable::new()?;
+        let pa = PhysAddr::from(table.entries.as_ptr());
+        self.entries[index] = Entry::new(pa, flag);
+        Ok(table)
     }
 
     fn px(level: u32, va: usize) -> usize {
@@ -174,9 +520,15 @@ impl KernelPgTable {
     /// Clones entries in the kernel page table. Use them as a template for user page tables.
     /// This method ensures all kernel memory mappings exist in user memory space.
     pub fn clone() -> PageTable {
-        let other = PageTable::new();
+        Self::try_clone().expect("no memory for a page table")
+    }
+
+    /// Like [`KernelPgTable::clone`], but fails with [`OutOfMemory`](crate::OsError::OutOfMemory)
+    /// instead of panicking.
+    pub fn try_clone() -> Result<PageTable> {
+        let other = PageTable::new()?;
         other.entries.copy_from_slice(Self::get().entries);
-        other
+        Ok(other)
     }
 
     /// Initializes the kernel page table which manages `ram_size` bytes of memory
@@ -190,25 +542,32 @@ impl KernelPgTable {
     /// paging capability. To strengthen memory protection, it's necessary to set up
     /// a fine-grained page table.
     pub fn init_inner(ram_size: usize) -> PageTable {
-        let mut root = PageTable::new();
+        let mut root = PageTable::new().expect("no memory for the kernel page table");
+        root.asid = Asid::kernel();
 
         // Kernel's code and data exist in all memory spaces, therefore the global bit is set.
         let rx = PTEFlags::R | PTEFlags::X | PTEFlags::G | PTEFlags::V;
+        let r = PTEFlags::R | PTEFlags::G | PTEFlags::V;
         let rw = PTEFlags::R | PTEFlags::W | PTEFlags::G | PTEFlags::V;
 
         extern "C" {
             fn etext();
+            fn erodata();
         }
 
-        let etext = etext as usize;
+        let (etext, erodata) = (etext as *const () as usize, erodata as *const () as usize);
         let kr_base = KERN_BASE + VM_OFFSET;
         let kr_end = VM_BASE + ram_size;
 
         // map kernel text executable and read-only.
         root.map(PhysAddr::from_pa(KERN_BASE), kr_base, etext - kr_base, rx);
 
-        // map kernel data and the physical RAM we'll make use of.
-        root.map(PhysAddr::from(etext), etext, kr_end - etext, rw);
+        // map kernel constants read-only.
+        root.map(PhysAddr::from(etext), etext, erodata - etext, r);
+
+        // map kernel data, the heap and the physical RAM we'll make use of,
+        // none of it executable.
+        root.map(PhysAddr::from(erodata), erodata, kr_end - erodata, rw);
 
         // PLIC
         root.map(PhysAddr::from(PLIC_BASE), PLIC_BASE, 0x400000, rw);
@@ -217,6 +576,9 @@ impl KernelPgTable {
         root.map(PhysAddr::from(MMIO_BASE), MMIO_BASE, PG_SIZE, rw);
 
         root.activate();
+        // The entry page table is gone, but the kernel page table keeps ASID
+        // 0, so nothing else drops its entries from the TLB.
+        root.flush_asid();
         root
     }
 
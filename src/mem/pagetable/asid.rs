@@ -0,0 +1,119 @@
+//! Address Space Identifiers
+//!
+//! A user page table gets an ASID when it's activated for the first time, and
+//! the TLB entries it brings in are tagged with it. Switching to a table that
+//! holds an ASID then needs no flush.
+//!
+//! ASIDs are handed out in increasing order and never freed one by one. Once
+//! they run out, a new generation starts: the whole TLB is flushed, and every
+//! table takes a new ASID at its next activation. ASID 0 is kept for the
+//! kernel page table, which only has global mappings.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+use crate::sync::{Intr, Lazy, Mutex};
+
+/// Position of the ASID field in `satp`
+pub const SATP_SHIFT: usize = 44;
+/// Width of the ASID field in `satp`, the most that Sv39 allows
+const MAX_BITS: usize = 16;
+
+/// Tag of the kernel page table, which always uses ASID 0
+const KERNEL: usize = usize::MAX;
+
+/// The ASID of a page table, together with the generation it belongs to.
+/// A tag of 0 means no ASID has been taken yet.
+pub struct Asid(AtomicUsize);
+
+struct Allocator {
+    /// The number of ASIDs the hart implements, 1 if it has none but 0
+    asids: usize,
+    generation: usize,
+    next: usize,
+}
+
+impl Allocator {
+    /// Finds the implemented ASID bits by writing ones to them.
+    fn new() -> Self {
+        let mask = ((1 << MAX_BITS) - 1) << SATP_SHIFT;
+        let probed: usize;
+        unsafe {
+            asm!(
+                "csrr {old}, satp",
+                "or {tmp}, {old}, {mask}",
+                "csrw satp, {tmp}",
+                "csrr {tmp}, satp",
+                "csrw satp, {old}",
+                old = out(reg) _,
+                tmp = out(reg) probed,
+                mask = in(reg) mask,
+            );
+        }
+
+        Self {
+            asids: ((probed & mask) >> SATP_SHIFT) + 1,
+            generation: 1,
+            next: 1,
+        }
+    }
+
+    fn instance() -> &'static Mutex<Allocator, Intr> {
+        static ASIDS: Lazy<Mutex<Allocator, Intr>> = Lazy::new(|| Mutex::new(Allocator::new()));
+
+        &ASIDS
+    }
+}
+
+impl Asid {
+    pub const fn new() -> Self {
+        Self(AtomicUsize::new(0))
+    }
+
+    pub const fn kernel() -> Self {
+        Self(AtomicUsize::new(KERNEL))
+    }
+
+    /// The ASID this table holds in the current generation, if any. A table
+    /// without one has no entries in the TLB.
+    pub fn current(&self) -> Option<usize> {
+        let allocator = Allocator::instance().lock();
+        match self.0.load(SeqCst) {
+            KERNEL => Some(0),
+            tag if tag >> MAX_BITS == allocator.generation => Some(tag & ((1 << MAX_BITS) - 1)),
+            _ => None,
+        }
+    }
+
+    /// Returns the ASID to activate the table with, taking a new one if the
+    /// table has none in the current generation. `None` means the hart has
+    /// no ASIDs, and the whole TLB has to be flushed on every switch.
+    pub fn get(&self) -> Option<usize> {
+        let mut allocator = Allocator::instance().lock();
+        if allocator.asids == 1 {
+            return None;
+        }
+
+        match self.0.load(SeqCst) {
+            KERNEL => return Some(0),
+            tag if tag >> MAX_BITS == allocator.generation => {
+                return Some(tag & ((1 << MAX_BITS) - 1))
+            }
+            _ => {}
+        }
+
+        if allocator.next == allocator.asids {
+            // Stale entries of the old generation might carry any ASID.
+            allocator.generation += 1;
+            allocator.next = 1;
+            unsafe { asm!("sfence.vma zero, zero") };
+        }
+
+        let asid = allocator.next;
+        allocator.next += 1;
+        self.0
+            .store(allocator.generation << MAX_BITS | asid, SeqCst);
+
+        Some(asid)
+    }
+}
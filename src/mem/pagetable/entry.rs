@@ -6,6 +6,9 @@ use crate::mem::utils::{PhysAddr, PG_SHIFT};
 /// The format of Sv39 page table entry:
 /// |  63-54 |  53-28 |  27-19 |  18-10 | 9-8 |7|6|5|4|3|2|1|0|
 /// | Unused | PPN[2] | PPN[1] | PPN[0] | RSW |D|A|G|U|X|W|R|V|
+///
+/// Bit 8 of RSW marks copy-on-write pages, and bit 9 marks pages that can be
+/// brought in again from the supplemental page table.
 #[repr(transparent)]
 #[derive(Clone, Copy, Debug)]
 pub struct Entry(usize);
@@ -28,6 +31,10 @@ bitflags::bitflags! {
         const A = 0b0100_0000;
         /// Dirty
         const D = 0b1000_0000;
+        /// Reserved for software: a shared page that is copied on the first write
+        const COW = 0b1_0000_0000;
+        /// Reserved for software: a page that can be brought in again on demand
+        const SPT = 0b10_0000_0000;
     }
 }
 
@@ -38,10 +45,15 @@ impl Entry {
         Entry((((pa.value() >> PG_SHIFT) & PPN_MASK) << Self::FLAG_SHIFT) | flags.bits())
     }
 
-    fn flag(&self) -> PTEFlags {
+    pub fn flag(&self) -> PTEFlags {
         PTEFlags::from_bits_truncate(self.0)
     }
 
+    /// Replaces all flags while keeping the physical address
+    pub fn set_flag(&mut self, flags: PTEFlags) {
+        self.0 = (self.0 & !PTEFlags::all().bits) | flags.bits();
+    }
+
     fn ppn(&self) -> usize {
         self.0 >> Self::FLAG_SHIFT & PPN_MASK
     }
@@ -75,6 +87,10 @@ impl Entry {
         self.flag().contains(PTEFlags::X)
     }
 
+    pub fn is_cow(&self) -> bool {
+        self.flag().contains(PTEFlags::COW)
+    }
+
     pub fn is_accessed(&self) -> bool {
         self.flag().contains(PTEFlags::A)
     }
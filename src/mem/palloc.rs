@@ -1,14 +1,17 @@
 //! Global Page Allocator
 
 use core::cmp::min;
+use core::mem::size_of;
+use core::{ptr, slice};
 
+use crate::mem::layout::{PM_BASE, VM_OFFSET};
 use crate::mem::utils::*;
 use crate::sync::{Intr, Lazy, Mutex};
+use crate::{OsError, Result};
 
-// BuddyAllocator allocates at most `1<<MAX_ORDER` pages at a time
+// Buddy lists hold chunks of at most `1<<MAX_ORDER` pages. Larger requests
+// take a run of adjacent chunks of that order.
 const MAX_ORDER: usize = 8;
-// How many pages are there in the user memory pool
-pub(super) const USER_POOL_LIMIT: usize = 256;
 
 /// Buddy Allocator. It allocates and deallocates memory page-wise.
 #[derive(Debug)]
@@ -19,6 +22,8 @@ struct BuddyAllocator {
     total: usize,
     /// The number of pages allocated
     allocated: usize,
+    /// The i-th counter is the number of live chunks of 2^i pages
+    live: [usize; MAX_ORDER + 1],
 }
 
 impl BuddyAllocator {
@@ -29,6 +34,7 @@ impl BuddyAllocator {
             free_lists: [InMemList::new(); MAX_ORDER + 1],
             total: 0,
             allocated: 0,
+            live: [0; MAX_ORDER + 1],
         }
     }
 
@@ -36,6 +42,9 @@ impl BuddyAllocator {
     unsafe fn insert_range(&mut self, start: usize, end: usize) {
         let start = round_up(start, PG_SIZE);
         let end = round_down(end, PG_SIZE);
+        if start >= end {
+            return;
+        }
         self.total += end - start;
 
         let mut current_start: usize = start;
@@ -53,9 +62,21 @@ impl BuddyAllocator {
         }
     }
 
-    /// Allocate n pages and returns the virtual address.
-    unsafe fn alloc(&mut self, n: usize) -> *mut u8 {
-        assert!(n <= 1 << MAX_ORDER, "request is too large");
+    /// The number of pages actually taken by a request of n pages
+    fn pages(n: usize) -> usize {
+        if n > 1 << MAX_ORDER {
+            round_up(n, 1 << MAX_ORDER)
+        } else {
+            n.next_power_of_two()
+        }
+    }
+
+    /// Allocate n pages and returns the virtual address, or `None` if there is
+    /// no large enough free chunk.
+    unsafe fn alloc(&mut self, n: usize) -> Option<*mut u8> {
+        if n > 1 << MAX_ORDER {
+            return self.alloc_range(n);
+        }
 
         let order = n.next_power_of_two().trailing_zeros() as usize;
         for i in order..self.free_lists.len() {
@@ -72,15 +93,65 @@ impl BuddyAllocator {
                     }
                 }
                 self.allocated += 1 << order;
-                return self.free_lists[order].pop().unwrap().cast();
+                self.live[order] += 1;
+                return self.free_lists[order].pop().map(|block| block.cast());
             }
         }
 
-        unreachable!("memory is exhausted");
+        None
+    }
+
+    /// Allocate more than `1<<MAX_ORDER` pages by searching for adjacent free
+    /// chunks of the maximum order.
+    unsafe fn alloc_range(&mut self, n: usize) -> Option<*mut u8> {
+        const CHUNK: usize = PG_SIZE << MAX_ORDER;
+        let chunks = Self::pages(n) >> MAX_ORDER;
+
+        // The lists are only read during the search, so copies of them are
+        // enough to walk through.
+        let is_free = |addr: usize| {
+            let mut list = self.free_lists[MAX_ORDER];
+            let found = list.iter_mut().any(|chunk| chunk.value() as usize == addr);
+            found
+        };
+        let mut list = self.free_lists[MAX_ORDER];
+        let start = list
+            .iter_mut()
+            .map(|chunk| chunk.value() as usize)
+            .find(|&start| (1..chunks).all(|i| is_free(start + i * CHUNK)))?;
+
+        for i in 0..chunks {
+            self.free_lists[MAX_ORDER]
+                .iter_mut()
+                .find(|chunk| chunk.value() as usize == start + i * CHUNK)
+                .unwrap()
+                .pop();
+        }
+        self.allocated += chunks << MAX_ORDER;
+        self.live[MAX_ORDER] += chunks;
+
+        Some(start as *mut u8)
+    }
+
+    /// The number of free pages
+    fn free(&self) -> usize {
+        self.total / PG_SIZE - self.allocated
     }
 
     /// Deallocate a chunk of pages
     unsafe fn dealloc(&mut self, ptr: *mut u8, n: usize) {
+        if n > 1 << MAX_ORDER {
+            // Chunks of the maximum order are never merged
+            let chunks = Self::pages(n) >> MAX_ORDER;
+            for i in 0..chunks {
+                let chunk = ptr as usize + (i << (MAX_ORDER + PG_SHIFT));
+                self.free_lists[MAX_ORDER].push(chunk as *mut usize);
+            }
+            self.allocated -= chunks << MAX_ORDER;
+            self.live[MAX_ORDER] -= chunks;
+            return;
+        }
+
         let order = n.next_power_of_two().trailing_zeros() as usize;
         self.free_lists[order].push(ptr.cast());
 
@@ -109,59 +180,274 @@ impl BuddyAllocator {
         }
 
         self.allocated -= 1 << order;
+        self.live[order] -= 1;
+    }
+}
+
+/// Limits on how physical frames are split between the kernel and user
+/// processes, in pages.
+#[derive(Debug, Clone, Copy)]
+pub struct Watermarks {
+    /// User frames never take more pages than this
+    pub user_max: usize,
+    /// User frames never bring free pages below this, which keeps memory for
+    /// the kernel. A user allocation that would go below it evicts clean user
+    /// pages instead.
+    pub kernel_min: usize,
+}
+
+impl Default for Watermarks {
+    fn default() -> Self {
+        Self {
+            user_max: usize::MAX,
+            kernel_min: 128,
+        }
     }
 }
 
-/// Wraps the buddy allocator
-pub struct Palloc(Lazy<Mutex<BuddyAllocator, Intr>>);
+/// Pages held by the kernel and user processes
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    pub total: usize,
+    pub free: usize,
+    pub kernel: usize,
+    pub user: usize,
+}
+
+/// The buddy allocator with kernel and user accounting
+struct FrameAllocator {
+    buddy: BuddyAllocator,
+    /// Pages allocated by the kernel
+    kernel: usize,
+    /// Pages allocated for user frames
+    user: usize,
+    watermarks: Watermarks,
+}
+
+impl FrameAllocator {
+    unsafe fn alloc_kernel(&mut self, n: usize) -> Option<*mut u8> {
+        let ptr = self.buddy.alloc(n)?;
+        self.kernel += BuddyAllocator::pages(n);
+        Some(ptr)
+    }
+
+    unsafe fn alloc_user(&mut self, n: usize) -> Option<*mut u8> {
+        let pages = BuddyAllocator::pages(n);
+        if self.user + pages > self.watermarks.user_max
+            || self.buddy.free() < pages + self.watermarks.kernel_min
+        {
+            return None;
+        }
+
+        let ptr = self.buddy.alloc(n)?;
+        self.user += pages;
+        Some(ptr)
+    }
+}
+
+/// Allocator of all physical frames, used by the kernel and [`UserPool`].
+pub struct Palloc(Lazy<Mutex<FrameAllocator, Intr>>);
 
 unsafe impl Sync for Palloc {}
 
 impl Palloc {
-    /// Initialize the page-based allocator
-    pub unsafe fn init(start: usize, end: usize) {
-        Self::instance().lock().insert_range(start, end);
+    /// Hands the memory from `start` to `end` over to the allocator. It may be
+    /// called again to add more memory.
+    ///
+    /// ## Safety
+    /// The range must be mapped kernel memory that nothing else uses, and must
+    /// not overlap memory handed over before.
+    pub unsafe fn insert_range(start: usize, end: usize) {
+        Self::instance().lock().buddy.insert_range(start, end);
     }
 
     /// Allocate n pages of a consecutive memory segment
+    ///
+    /// Requests over `1<<MAX_ORDER` pages are rounded up to a multiple of that
+    /// size and may fail on fragmented memory even if enough pages are free.
+    /// The kernel may use pages kept by [`Watermarks::kernel_min`].
+    ///
+    /// Panics if memory is exhausted, see [`Palloc::try_alloc`].
+    ///
+    /// ## Safety
+    /// The pages are uninitialized, and must be given back with
+    /// [`Palloc::dealloc`] with the same `n`.
     pub unsafe fn alloc(n: usize) -> *mut u8 {
-        Self::instance().lock().alloc(n)
+        Self::try_alloc(n).expect("memory is exhausted")
+    }
+
+    /// Like [`Palloc::alloc`], but fails with [`OsError::OutOfMemory`] instead
+    /// of panicking.
+    ///
+    /// If no pages are free, clean user pages of other processes are evicted
+    /// until the request fits, and it only fails once nothing is left to
+    /// evict.
+    ///
+    /// ## Safety
+    /// See [`Palloc::alloc`]. The caller must also not hold the lock of a user
+    /// page table other than the current one, as eviction takes it.
+    pub unsafe fn try_alloc(n: usize) -> Result<*mut u8> {
+        loop {
+            if let Some(ptr) = Self::instance().lock().alloc_kernel(n) {
+                return Ok(ptr);
+            }
+            if crate::userproc::reclaim_others(n) == 0 {
+                return Err(OsError::OutOfMemory);
+            }
+        }
     }
 
     /// Free n pages of memory starting at `ptr`
+    ///
+    /// ## Safety
+    /// `ptr` must come from [`Palloc::alloc`] or [`Palloc::try_alloc`] of `n`
+    /// pages, and must not be used afterwards.
     pub unsafe fn dealloc(ptr: *mut u8, n: usize) {
-        Self::instance().lock().dealloc(ptr, n)
+        let mut frames = Self::instance().lock();
+        frames.buddy.dealloc(ptr, n);
+        frames.kernel -= BuddyAllocator::pages(n);
     }
 
-    fn instance() -> &'static Mutex<BuddyAllocator, Intr> {
-        static PALLOC: Palloc = Palloc(Lazy::new(|| Mutex::new(BuddyAllocator::empty())));
+    /// Number of live chunks per order, where a chunk of order i has 2^i pages
+    pub fn live_chunks() -> [usize; MAX_ORDER + 1] {
+        Self::instance().lock().buddy.live
+    }
+
+    pub fn usage() -> Usage {
+        let frames = Self::instance().lock();
+        Usage {
+            total: frames.buddy.total / PG_SIZE,
+            free: frames.buddy.free(),
+            kernel: frames.kernel,
+            user: frames.user,
+        }
+    }
+
+    pub fn watermarks() -> Watermarks {
+        Self::instance().lock().watermarks
+    }
+
+    /// Adjusts the watermarks. User frames over the new limits are reclaimed
+    /// by the next allocations that run short.
+    pub fn set_watermarks(watermarks: Watermarks) {
+        Self::instance().lock().watermarks = watermarks;
+    }
+
+    fn instance() -> &'static Mutex<FrameAllocator, Intr> {
+        static PALLOC: Palloc = Palloc(Lazy::new(|| {
+            Mutex::new(FrameAllocator {
+                buddy: BuddyAllocator::empty(),
+                kernel: 0,
+                user: 0,
+                watermarks: Watermarks::default(),
+            })
+        }));
 
         &PALLOC.0
     }
 }
 
-pub struct UserPool(Lazy<Mutex<BuddyAllocator, Intr>>);
+/// Allocator of user frames, which are taken from [`Palloc`].
+///
+/// A frame may be mapped by several page tables at the same time, e.g. after a
+/// copy-on-write fork. Such frames are reference counted, and only go back to
+/// [`Palloc`] after their last owner deallocates them. The pool keeps the
+/// number of extra owners of every frame of RAM, indexed by frame number, so
+/// that counting an owner never allocates.
+pub struct UserPool(Lazy<Mutex<&'static mut [u32], Intr>>);
 
 unsafe impl Sync for UserPool {}
 
 impl UserPool {
+    /// Sets up the owner counts for RAM, which ends at physical address
+    /// `ram_end`.
+    ///
+    /// ## Safety
+    /// It must be called once, after [`Palloc`] has memory, and before any
+    /// user frame is allocated.
+    pub unsafe fn init(ram_end: usize) {
+        let frames = (ram_end - PM_BASE) / PG_SIZE;
+        let pages = (frames * size_of::<u32>() + PG_SIZE - 1) / PG_SIZE;
+        let extra = Palloc::alloc(pages) as *mut u32;
+        ptr::write_bytes(extra, 0, frames);
+        *Self::instance().lock() = slice::from_raw_parts_mut(extra, frames);
+    }
+
     /// Allocate n pages of consecutive space
+    ///
+    /// If the [`Watermarks`] don't allow it, clean user pages are evicted first.
+    ///
+    /// Panics if user memory is exhausted, see [`UserPool::try_alloc_pages`].
+    ///
+    /// ## Safety
+    /// The pages are uninitialized, and every owner must give them back with
+    /// [`UserPool::dealloc_pages`] with the same `n`. The caller must also not
+    /// hold the lock of any user page table, as eviction takes it.
     pub unsafe fn alloc_pages(n: usize) -> *mut u8 {
-        Self::instance().lock().alloc(n)
+        Self::try_alloc_pages(n).expect("user memory is exhausted")
+    }
+
+    /// Like [`UserPool::alloc_pages`], but fails with [`OsError::OutOfMemory`]
+    /// once nothing is left to evict.
+    ///
+    /// ## Safety
+    /// See [`UserPool::alloc_pages`].
+    pub unsafe fn try_alloc_pages(n: usize) -> Result<*mut u8> {
+        loop {
+            if let Some(ptr) = Palloc::instance().lock().alloc_user(n) {
+                return Ok(ptr);
+            }
+            if crate::userproc::reclaim(n) == 0 {
+                return Err(OsError::OutOfMemory);
+            }
+        }
     }
 
     /// Free n pages of memory starting at `ptr`
+    ///
+    /// If the page is shared, only one reference is dropped.
+    ///
+    /// ## Safety
+    /// `ptr` must come from [`UserPool::alloc_pages`] or
+    /// [`UserPool::try_alloc_pages`] of `n` pages, and the caller must own a
+    /// reference to them, which it must not use afterwards.
     pub unsafe fn dealloc_pages(ptr: *mut u8, n: usize) {
-        Self::instance().lock().dealloc(ptr, n)
+        {
+            let mut extra = Self::instance().lock();
+            let extra = &mut extra[Self::frame(ptr)];
+            if *extra > 0 {
+                assert_eq!(n, 1, "only single pages can be shared");
+                *extra -= 1;
+                return;
+            }
+        }
+
+        let mut frames = Palloc::instance().lock();
+        frames.buddy.dealloc(ptr, n);
+        frames.user -= BuddyAllocator::pages(n);
+    }
+
+    /// Adds an owner to an allocated page
+    ///
+    /// ## Safety
+    /// `ptr` must be a single page from [`UserPool`] that is still owned. The
+    /// new owner must give it back with [`UserPool::dealloc_pages`].
+    pub unsafe fn share_page(ptr: *mut u8) {
+        Self::instance().lock()[Self::frame(ptr)] += 1;
+    }
+
+    /// The number of owners of an allocated page
+    pub fn page_refs(ptr: *mut u8) -> usize {
+        Self::instance().lock()[Self::frame(ptr)] as usize + 1
     }
 
-    /// Initialize the page-based allocator
-    pub unsafe fn init(start: usize, end: usize) {
-        Self::instance().lock().insert_range(start, end);
+    /// The frame number of the page at `ptr`
+    fn frame(ptr: *mut u8) -> usize {
+        (ptr as usize - VM_OFFSET - PM_BASE) / PG_SIZE
     }
 
-    fn instance() -> &'static Mutex<BuddyAllocator, Intr> {
-        static USERPOOL: UserPool = UserPool(Lazy::new(|| Mutex::new(BuddyAllocator::empty())));
+    fn instance() -> &'static Mutex<&'static mut [u32], Intr> {
+        static USERPOOL: UserPool = UserPool(Lazy::new(|| Mutex::new(&mut [])));
 
         &USERPOOL.0
     }
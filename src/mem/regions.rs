@@ -0,0 +1,73 @@
+//! Physical memory regions
+//!
+//! The device tree describes which ranges of physical memory are RAM and which
+//! of them are reserved. They are collected here before memory management is
+//! up, so no heap is involved.
+
+use core::cmp::{max, min};
+
+/// The most ranges a [`Regions`] can hold
+const MAX_REGIONS: usize = 32;
+
+/// A set of disjoint ranges of physical addresses
+#[derive(Clone)]
+pub struct Regions {
+    ranges: [(usize, usize); MAX_REGIONS],
+    len: usize,
+}
+
+impl Default for Regions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Regions {
+    pub const fn new() -> Self {
+        Self {
+            ranges: [(0, 0); MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    /// Adds the range `[start, end)`, which must not overlap other ranges.
+    pub fn insert(&mut self, start: usize, end: usize) {
+        if start >= end {
+            return;
+        }
+        assert!(self.len < MAX_REGIONS, "too many memory regions");
+        assert!(
+            self.clip(start, end).next().is_none(),
+            "overlapping memory regions"
+        );
+
+        self.ranges[self.len] = (start, end);
+        self.len += 1;
+    }
+
+    /// Takes the range `[start, end)` out of the set.
+    pub fn remove(&mut self, start: usize, end: usize) {
+        let old = self.clone();
+        self.len = 0;
+        for (s, e) in old.iter() {
+            self.insert(s, min(e, start));
+            self.insert(max(s, end), e);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.ranges[..self.len].iter().copied()
+    }
+
+    /// The parts of the set within `[start, end)`
+    pub fn clip(&self, start: usize, end: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.iter()
+            .map(move |(s, e)| (max(s, start), min(e, end)))
+            .filter(|(s, e)| s < e)
+    }
+
+    /// The end of the highest range
+    pub fn end(&self) -> usize {
+        self.iter().map(|(_, end)| end).max().unwrap_or(0)
+    }
+}
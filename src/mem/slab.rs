@@ -0,0 +1,388 @@
+//! Object caches
+//!
+//! A [`Cache`] serves objects of a single type. Objects are packed by their
+//! exact size into slabs, which are blocks of pages taken from [`Palloc`],
+//! instead of being rounded up to a power of two as [`Heap`](super::malloc::Heap)
+//! does. A slab whose objects are all freed is given back to [`Palloc`].
+//!
+//! Objects are either managed by hand with [`Cache::alloc`] and [`Cache::free`],
+//! or owned by a [`CacheBox`], which returns them to their cache when dropped.
+
+use core::alloc::Layout;
+use core::cmp::max;
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+
+use crate::mem::palloc::Palloc;
+use crate::mem::utils::*;
+use crate::sync::{Intr, Mutex};
+use crate::Result;
+
+const SLAB_MAGIC: u32 = 0x51ab0bec;
+/// A slab is made large enough to hold at least this many objects
+const MIN_OBJS_PER_SLAB: usize = 8;
+/// The largest slab, in pages, that [`Palloc`] can hand out at once
+const MAX_SLAB_PAGES: usize = 256;
+/// How many empty slabs a cache keeps for later allocations
+const MAX_EMPTY_SLABS: usize = 1;
+
+/// Metadata at the beginning of every slab
+///
+/// A slab spans [`Geometry::pages`] pages and is aligned to its size, so the
+/// slab of an object is found by masking the object's address.
+#[repr(C)]
+struct Slab {
+    /// Always set to [`SLAB_MAGIC`]
+    magic: u32,
+    /// The number of objects handed out from this slab
+    inuse: u32,
+    free_list: InMemList,
+    prev: *mut Slab,
+    next: *mut Slab,
+}
+
+/// An intrusive doubly linked list of slabs
+struct SlabList {
+    head: *mut Slab,
+    len: usize,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        Self {
+            head: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    fn first(&self) -> Option<*mut Slab> {
+        (!self.head.is_null()).then_some(self.head)
+    }
+
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = slab;
+        }
+        self.head = slab;
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut Slab) {
+        let (prev, next) = ((*slab).prev, (*slab).next);
+        match prev.is_null() {
+            true => self.head = next,
+            false => (*prev).next = next,
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        self.len -= 1;
+    }
+}
+
+/// How objects of a certain layout are laid out in a slab
+#[derive(Clone, Copy)]
+struct Geometry {
+    /// Size of an object, padded to its alignment
+    obj_size: usize,
+    /// Offset of the first object from the start of the slab
+    offset: usize,
+    /// Size of a slab in pages, always a power of two
+    pages: usize,
+    objs_per_slab: usize,
+}
+
+impl Geometry {
+    fn new(layout: Layout) -> Self {
+        // A free object holds a link of the slab's free list
+        let align = max(layout.align(), size_of::<usize>());
+        let obj_size = round_up(max(layout.size(), size_of::<usize>()), align);
+        let offset = round_up(size_of::<Slab>(), align);
+
+        let mut pages = 1;
+        while (pages * PG_SIZE - offset) / obj_size < MIN_OBJS_PER_SLAB {
+            pages *= 2;
+        }
+        assert!(pages <= MAX_SLAB_PAGES, "object is too large for a cache");
+
+        Self {
+            obj_size,
+            offset,
+            pages,
+            objs_per_slab: (pages * PG_SIZE - offset) / obj_size,
+        }
+    }
+}
+
+/// Statistics of a [`Cache`]
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub name: &'static str,
+    /// Size of an object, including padding
+    pub obj_size: usize,
+    /// Pages per slab
+    pub slab_pages: usize,
+    /// Slabs currently owned by the cache
+    pub slabs: usize,
+    /// Objects handed out and not yet freed
+    pub inuse: usize,
+    /// Objects the owned slabs are able to hold
+    pub capacity: usize,
+    /// Allocations made since the cache was created
+    pub allocs: usize,
+    /// Slabs returned to [`Palloc`] since the cache was created
+    pub reclaimed: usize,
+}
+
+/// The untyped part of a cache
+struct RawCache {
+    name: &'static str,
+    geo: Geometry,
+    /// Slabs with both free and allocated objects
+    partial: SlabList,
+    /// Slabs with no free object
+    full: SlabList,
+    /// Slabs with no allocated object
+    empty: SlabList,
+    inuse: usize,
+    allocs: usize,
+    reclaimed: usize,
+}
+
+impl RawCache {
+    fn slabs(&self) -> usize {
+        self.partial.len + self.full.len + self.empty.len
+    }
+
+    /// Carves a new slab out of [`Palloc`]
+    ///
+    /// ## Errors
+    /// [`OutOfMemory`](crate::OsError::OutOfMemory) if no pages are left for the slab.
+    unsafe fn grow(&mut self) -> Result<*mut Slab> {
+        let slab = Palloc::try_alloc(self.geo.pages)? as *mut Slab;
+        assert!(slab as usize % (self.geo.pages * PG_SIZE) == 0);
+
+        slab.write(Slab {
+            magic: SLAB_MAGIC,
+            inuse: 0,
+            free_list: InMemList::new(),
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+        });
+        // Push in reverse so that objects are handed out in address order
+        for i in (0..self.geo.objs_per_slab).rev() {
+            let obj = slab as usize + self.geo.offset + i * self.geo.obj_size;
+            (*slab).free_list.push(obj as *mut usize);
+        }
+
+        Ok(slab)
+    }
+
+    /// Retrieves the slab that `obj` lies in
+    unsafe fn slab_of(&self, obj: *mut u8) -> *mut Slab {
+        let slab_size = self.geo.pages * PG_SIZE;
+        let slab = (obj as usize & !(slab_size - 1)) as *mut Slab;
+        assert_eq!((*slab).magic, SLAB_MAGIC);
+        // Make sure the object is one that was handed out
+        let offset = obj as usize - slab as usize;
+        assert!(offset >= self.geo.offset && (offset - self.geo.offset) % self.geo.obj_size == 0);
+        slab
+    }
+
+    unsafe fn alloc(&mut self) -> Result<*mut u8> {
+        let slab = match (self.partial.first(), self.empty.first()) {
+            (Some(slab), _) => slab,
+            (None, Some(slab)) => {
+                self.empty.remove(slab);
+                self.partial.push(slab);
+                slab
+            }
+            (None, None) => {
+                let slab = self.grow()?;
+                self.partial.push(slab);
+                slab
+            }
+        };
+
+        let obj = (*slab).free_list.pop().unwrap() as *mut u8;
+        (*slab).inuse += 1;
+        if (*slab).inuse as usize == self.geo.objs_per_slab {
+            self.partial.remove(slab);
+            self.full.push(slab);
+        }
+
+        self.inuse += 1;
+        self.allocs += 1;
+        Ok(obj)
+    }
+
+    unsafe fn free(&mut self, obj: *mut u8) {
+        let slab = self.slab_of(obj);
+        assert!((*slab).inuse > 0, "double free");
+
+        if (*slab).inuse as usize == self.geo.objs_per_slab {
+            self.full.remove(slab);
+            self.partial.push(slab);
+        }
+        (*slab).free_list.push(obj.cast());
+        (*slab).inuse -= 1;
+        self.inuse -= 1;
+
+        if (*slab).inuse == 0 {
+            self.partial.remove(slab);
+            match self.empty.len < MAX_EMPTY_SLABS {
+                true => self.empty.push(slab),
+                false => self.release(slab),
+            }
+        }
+    }
+
+    /// Gives an empty slab back to [`Palloc`]
+    unsafe fn release(&mut self, slab: *mut Slab) {
+        (*slab).magic = 0;
+        Palloc::dealloc(slab.cast(), self.geo.pages);
+        self.reclaimed += 1;
+    }
+
+    /// Releases all empty slabs
+    fn shrink(&mut self) {
+        while let Some(slab) = self.empty.first() {
+            unsafe {
+                self.empty.remove(slab);
+                self.release(slab);
+            }
+        }
+    }
+}
+
+/// A cache of objects of type `T`
+///
+/// Memory returned by [`Cache::alloc`] is uninitialized.
+pub struct Cache<T> {
+    inner: Mutex<RawCache, Intr>,
+    phantom: PhantomData<T>,
+}
+
+unsafe impl<T> Send for Cache<T> {}
+unsafe impl<T> Sync for Cache<T> {}
+
+impl<T> Cache<T> {
+    /// Creates an empty cache. No memory is taken until the first allocation.
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            inner: Mutex::new(RawCache {
+                name,
+                geo: Geometry::new(Layout::new::<T>()),
+                partial: SlabList::new(),
+                full: SlabList::new(),
+                empty: SlabList::new(),
+                inuse: 0,
+                allocs: 0,
+                reclaimed: 0,
+            }),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Allocates memory for an object
+    ///
+    /// ## Errors
+    /// [`OutOfMemory`](crate::OsError::OutOfMemory) if no pages are left for a new slab.
+    pub fn alloc(&self) -> Result<*mut T> {
+        unsafe { self.inner.lock().alloc().map(|obj| obj.cast()) }
+    }
+
+    /// Returns an object to the cache. The object is not dropped.
+    ///
+    /// ## Safety
+    /// `obj` must come from [`Cache::alloc`] of this cache, and must not be used
+    /// afterwards.
+    pub unsafe fn free(&self, obj: *mut T) {
+        self.inner.lock().free(obj.cast())
+    }
+
+    /// Gives all empty slabs back to [`Palloc`]
+    pub fn shrink(&self) {
+        self.inner.lock().shrink()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock();
+        CacheStats {
+            name: inner.name,
+            obj_size: inner.geo.obj_size,
+            slab_pages: inner.geo.pages,
+            slabs: inner.slabs(),
+            inuse: inner.inuse,
+            capacity: inner.slabs() * inner.geo.objs_per_slab,
+            allocs: inner.allocs,
+            reclaimed: inner.reclaimed,
+        }
+    }
+}
+
+impl<T> Drop for Cache<T> {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock();
+        assert_eq!(
+            inner.inuse, 0,
+            "cache {} dropped with live objects",
+            inner.name
+        );
+        inner.shrink();
+    }
+}
+
+/// An object of a [`Cache`], which is dropped and returned to the cache when
+/// the box is dropped
+pub struct CacheBox<T: 'static> {
+    obj: NonNull<T>,
+    cache: &'static Cache<T>,
+}
+
+unsafe impl<T: Send> Send for CacheBox<T> {}
+unsafe impl<T: Sync> Sync for CacheBox<T> {}
+
+impl<T> CacheBox<T> {
+    /// Moves `value` into an object of `cache`.
+    ///
+    /// ## Errors
+    /// [`OutOfMemory`](crate::OsError::OutOfMemory) if no pages are left for a new slab.
+    pub fn try_new(cache: &'static Cache<T>, value: T) -> Result<Self> {
+        let obj = cache.alloc()?;
+        unsafe {
+            obj.write(value);
+            Ok(Self {
+                obj: NonNull::new_unchecked(obj),
+                cache,
+            })
+        }
+    }
+}
+
+impl<T> Deref for CacheBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.obj.as_ref() }
+    }
+}
+
+impl<T> DerefMut for CacheBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.obj.as_mut() }
+    }
+}
+
+impl<T> Drop for CacheBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.obj.as_ptr());
+            self.cache.free(self.obj.as_ptr());
+        }
+    }
+}
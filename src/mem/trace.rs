@@ -0,0 +1,224 @@
+//! Allocation tracing
+//!
+//! With feature `mem-trace`, every live heap allocation is recorded together
+//! with where it comes from: the call site of [`kalloc`](super::kalloc), or the
+//! subsystem tag active when a `GlobalAlloc` allocation (`Box`, `Vec`, `Arc`,
+//! ...) was made. [`report`] prints the allocations that are still live, along
+//! with the counters of every size class of the heap and [`Palloc`].
+//!
+//! Records live in a fixed hash table keyed by address, since the tracer must
+//! not allocate itself. Allocations made while the table is full are only
+//! counted, and [`report`] warns about them.
+
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::mem;
+
+pub use crate::mem::malloc::Site;
+
+use crate::mem::malloc::Heap;
+use crate::mem::palloc::Palloc;
+use crate::mem::PG_SIZE;
+use crate::sync::{Intr, Lazy, Mutex};
+use crate::thread::{self, Manager, Thread};
+
+/// Capacity of the record table, a power of two
+const MAX_RECORDS: usize = 4096;
+/// Records kept at most, which leaves enough free slots to keep probing short
+const MAX_LIVE: usize = MAX_RECORDS / 4 * 3;
+pub(crate) const UNTAGGED: &str = "untagged";
+
+#[derive(Clone, Copy)]
+struct Record {
+    ptr: usize,
+    size: usize,
+    site: Site,
+}
+
+struct State {
+    /// Records in the table
+    live: usize,
+    /// Allocations that didn't fit in the table
+    untracked: usize,
+    /// Tag of the running thread
+    tag: &'static str,
+}
+
+/// Guards [`TABLE`] as well.
+static STATE: Lazy<Mutex<State, Intr>> = Lazy::new(|| {
+    Mutex::new(State {
+        live: 0,
+        untracked: 0,
+        tag: UNTAGGED,
+    })
+});
+
+/// Kept in BSS, as the table is too large for a kernel stack.
+struct Table(UnsafeCell<[Option<Record>; MAX_RECORDS]>);
+
+unsafe impl Sync for Table {}
+
+impl Table {
+    /// ## Safety
+    /// The caller must hold the lock of [`STATE`].
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn get(&self) -> &mut [Option<Record>; MAX_RECORDS] {
+        &mut *self.0.get()
+    }
+}
+
+static TABLE: Table = Table(UnsafeCell::new([None; MAX_RECORDS]));
+
+/// The slot where the probe sequence for `ptr` starts
+fn home(ptr: usize) -> usize {
+    // Fibonacci hashing of the address, whose low bits are always clear.
+    (ptr >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (usize::BITS - MAX_RECORDS.trailing_zeros())
+}
+
+/// The slot after `slot`, wrapping around
+fn next(slot: usize) -> usize {
+    (slot + 1) % MAX_RECORDS
+}
+
+/// How many slots the probe sequence went from `from` to `to`
+fn distance(from: usize, to: usize) -> usize {
+    to.wrapping_sub(from) % MAX_RECORDS
+}
+
+/// Tags `GlobalAlloc` allocations of the current thread with a subsystem name
+/// until dropped. Tags nest, and the innermost one wins.
+pub struct Tag(&'static str);
+
+impl Tag {
+    pub fn new(tag: &'static str) -> Self {
+        let mut state = STATE.lock();
+        let old = state.tag;
+        state.tag = tag;
+        Tag(old)
+    }
+}
+
+impl Drop for Tag {
+    fn drop(&mut self) {
+        STATE.lock().tag = self.0;
+    }
+}
+
+/// Puts the tag of the running thread aside in `previous`, and activates the
+/// one of `next`. Runs on every context switch, so each thread has a tag of its
+/// own.
+pub(crate) fn switch(previous: &Thread, next: &Thread) {
+    let mut state = STATE.lock();
+    *previous.trace_tag.lock() = mem::replace(&mut state.tag, *next.trace_tag.lock());
+}
+
+pub(super) fn current_tag() -> &'static str {
+    STATE.lock().tag
+}
+
+pub(super) fn record(ptr: *mut u8, size: usize, site: Site) {
+    if size == 0 {
+        return;
+    }
+
+    let mut state = STATE.lock();
+    if state.live == MAX_LIVE {
+        state.untracked += 1;
+        return;
+    }
+
+    let table = unsafe { TABLE.get() };
+    let mut slot = home(ptr as usize);
+    while table[slot].is_some() {
+        slot = next(slot);
+    }
+    table[slot] = Some(Record {
+        ptr: ptr as usize,
+        size,
+        site,
+    });
+    state.live += 1;
+}
+
+pub(super) fn forget(ptr: *mut u8) {
+    let mut state = STATE.lock();
+    let table = unsafe { TABLE.get() };
+
+    let mut hole = home(ptr as usize);
+    loop {
+        match table[hole] {
+            Some(record) if record.ptr == ptr as usize => break,
+            Some(_) => hole = next(hole),
+            // Not recorded, as the table was full.
+            None => return,
+        }
+    }
+    state.live -= 1;
+
+    // Move records behind the hole back into it, unless their probe sequence
+    // starts after it, so that no sequence runs into an empty slot early.
+    let mut slot = hole;
+    loop {
+        slot = next(slot);
+        let record = match table[slot] {
+            Some(record) => record,
+            None => break,
+        };
+        if distance(home(record.ptr), slot) >= distance(hole, slot) {
+            table[hole] = Some(record);
+            hole = slot;
+        }
+    }
+    table[hole] = None;
+}
+
+/// Prints the threads that are still alive, all live allocations, and the
+/// counters of every size class.
+pub fn report() {
+    // Allocations of these threads are not leaks yet.
+    kprintln!("Threads still alive:");
+    let current = thread::current();
+    Manager::get().for_each(|thread| {
+        if !Arc::ptr_eq(thread, &current) {
+            kprintln!("  {:?}", thread);
+        }
+    });
+    drop(current);
+
+    kprintln!("Live heap allocations:");
+    let (mut count, mut bytes) = (0, 0);
+    let state = STATE.lock();
+    for record in unsafe { TABLE.get().iter() }.flatten() {
+        kprintln!(
+            "  {:#x} {:>6} bytes at {}",
+            record.ptr,
+            record.size,
+            record.site
+        );
+        count += 1;
+        bytes += record.size;
+    }
+    kprintln!("{} allocations, {} bytes", count, bytes);
+    if state.untracked > 0 {
+        kprintln!(
+            "Warning: {} allocations were not recorded, as the table was full. Leaks among them are missing above.",
+            state.untracked
+        );
+    }
+    drop(state);
+
+    kprintln!("Heap size classes (block size: live / allocated):");
+    for (block_size, live, allocs) in Heap::get().classes() {
+        kprintln!("  {:>4}: {} / {}", block_size, live, allocs);
+    }
+
+    kprintln!("Palloc chunks (pages: live):");
+    for (order, live) in Palloc::live_chunks().iter().enumerate() {
+        kprintln!(
+            "  {:>3}: {} ({} bytes)",
+            1 << order,
+            live,
+            live * (PG_SIZE << order)
+        );
+    }
+}
@@ -1,11 +1,166 @@
 #![allow(dead_code)]
 
-use core::arch::global_asm;
+use core::arch::{asm, global_asm};
+use core::cmp::min;
+use core::iter;
+use core::slice;
+
+use riscv::register::sstatus;
 
 use crate::error::OsError;
-use crate::mem::in_kernel_space;
+use crate::mem::{in_kernel_space, PageAlign, Pinned, Translate, PG_SIZE, VM_OFFSET};
 use crate::Result;
 
+/// Checks that the `len` bytes at `addr` lie in user space.
+fn check_range(addr: usize, len: usize) -> Result<()> {
+    match addr.checked_add(len) {
+        _ if len == 0 => Ok(()),
+        Some(end) if !in_kernel_space(addr) && !in_kernel_space(end - 1) => Ok(()),
+        _ => Err(OsError::BadPtr),
+    }
+}
+
+/// Lets the kernel access user pages while it lives.
+///
+/// `SUM` is clear everywhere else, so that a stray dereference of a user
+/// pointer faults instead of silently working. Only the routines below, whose
+/// faults are fixed up through the exception table, run under this guard.
+struct UserAccess(bool);
+
+impl UserAccess {
+    fn new() -> Self {
+        let sum = sstatus::read().sum();
+        unsafe { sstatus::set_sum() };
+        Self(sum)
+    }
+}
+
+impl Drop for UserAccess {
+    fn drop(&mut self) {
+        if !self.0 {
+            unsafe { sstatus::clear_sum() };
+        }
+    }
+}
+
+/// Copies `dst.len()` bytes from the user address `src` into `dst`.
+///
+/// Pages that are not resident yet are brought in on the way.
+///
+/// ## Errors
+/// [`OsError::BadPtr`] if the range isn't in user space, or any byte of it
+/// can't be read.
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<()> {
+    check_range(src, dst.len())?;
+    let _access = UserAccess::new();
+    match unsafe { __knrl_copy_usr(dst.as_mut_ptr(), src as *const u8, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(OsError::BadPtr),
+    }
+}
+
+/// Copies `src` to the user address `dst`.
+///
+/// ## Errors
+/// [`OsError::BadPtr`] if the range isn't in user space, or any byte of it
+/// can't be written.
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<()> {
+    check_range(dst, src.len())?;
+    let _access = UserAccess::new();
+    match unsafe { __knrl_copy_usr(dst as *mut u8, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(OsError::BadPtr),
+    }
+}
+
+/// Copies a NUL-terminated string from the user address `src` into `dst`, with
+/// its terminator, but at most `dst.len()` bytes.
+///
+/// ## Return
+/// The length of the string without the terminator. It's `dst.len()` if no
+/// terminator was found within `dst.len()` bytes.
+///
+/// ## Errors
+/// [`OsError::BadPtr`] if a byte before the terminator isn't in user space,
+/// or can't be read.
+pub fn strncpy_from_user(dst: &mut [u8], src: usize) -> Result<usize> {
+    // The string must end before kernel space.
+    let len = match src {
+        addr if in_kernel_space(addr) => return Err(OsError::BadPtr),
+        addr => min(dst.len(), VM_OFFSET - addr),
+    };
+
+    let _access = UserAccess::new();
+    match unsafe { __knrl_strncpy_usr(dst.as_mut_ptr(), src as *const u8, len) } {
+        -1 => Err(OsError::BadPtr),
+        n if n as usize == len && len < dst.len() => Err(OsError::BadPtr),
+        n => Ok(n as usize),
+    }
+}
+
+/// A buffer in user space, which may span several pages
+pub struct UserBuf {
+    addr: usize,
+    len: usize,
+}
+
+impl UserBuf {
+    /// ## Errors
+    /// [`OsError::BadPtr`] if the buffer isn't entirely in user space.
+    pub fn new(addr: *const u8, len: usize) -> Result<Self> {
+        check_range(addr as usize, len)?;
+        Ok(Self {
+            addr: addr as usize,
+            len,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Pieces of the buffer that don't cross pages, as their user addresses
+    /// and lengths.
+    fn pieces(&self) -> impl Iterator<Item = (usize, usize)> {
+        let end = self.addr + self.len;
+        let mut va = self.addr;
+        iter::from_fn(move || {
+            if va >= end {
+                return None;
+            }
+            let next = min(va.floor() + PG_SIZE, end);
+            let piece = (va, next - va);
+            va = next;
+            Some(piece)
+        })
+    }
+
+    /// Iterates over the buffer page by page, each piece translated to kernel
+    /// memory. Pages are brought in as needed, and stay pinned while their
+    /// piece lives. A piece that can't be read yields [`OsError::BadPtr`].
+    pub fn pages(&self) -> impl Iterator<Item = Result<Pinned<&[u8]>>> {
+        self.pieces().map(|(va, len)| {
+            unsafe { slice::from_raw_parts(va as *const u8, len) }
+                .translate()
+                .ok_or(OsError::BadPtr)
+        })
+    }
+
+    /// Like [`UserBuf::pages`], but for writing. Copy-on-write pages are
+    /// copied, and pages are marked dirty.
+    pub fn pages_mut(&mut self) -> impl Iterator<Item = Result<Pinned<&mut [u8]>>> {
+        self.pieces().map(|(va, len)| {
+            unsafe { slice::from_raw_parts_mut(va as *mut u8, len) }
+                .translate()
+                .ok_or(OsError::BadPtr)
+        })
+    }
+}
+
 /// Read a single byte from user space.
 ///
 /// ## Return
@@ -17,6 +172,7 @@ fn read_user_byte(user_src: *const u8) -> Result<u8> {
     }
 
     let byte: u8 = 0;
+    let _access = UserAccess::new();
     let ret_status: u8 = unsafe { __knrl_read_usr_byte(user_src, &byte as *const u8) };
 
     if ret_status == 0 {
@@ -36,6 +192,7 @@ fn write_user_byte(user_src: *const u8, value: u8) -> Result<()> {
         return Err(OsError::BadPtr);
     }
 
+    let _access = UserAccess::new();
     let ret_status: u8 = unsafe { __knrl_write_usr_byte(user_src, value) };
 
     if ret_status == 0 {
@@ -45,43 +202,147 @@ fn write_user_byte(user_src: *const u8, value: u8) -> Result<()> {
     }
 }
 
+/// Replaces the word at the user address `uaddr` with `new` if it holds `old`,
+/// atomically, as futexes need.
+///
+/// ## Return
+/// The word `uaddr` held.
+///
+/// ## Errors
+/// [`OsError::BadPtr`] if the word isn't aligned, isn't in user space, or
+/// can't be read and written.
+pub fn cmpxchg_user(uaddr: usize, old: u32, new: u32) -> Result<u32> {
+    if uaddr % 4 != 0 {
+        return Err(OsError::BadPtr);
+    }
+    check_range(uaddr, 4)?;
+
+    let _access = UserAccess::new();
+    let (value, fault): (isize, usize);
+    // Atomics are only available to inline assembly, so the exception table
+    // entries are written here rather than by the `extable` macro.
+    unsafe {
+        asm!(
+            "1:  lr.w.aqrl {value}, ({uaddr})",
+            "    li {fault}, 0",
+            "    bne {value}, {old}, 3f",
+            "2:  sc.w.aqrl {fault}, {new}, ({uaddr})",
+            "    bnez {fault}, 1b",
+            "    j 3f",
+            "4:  li {fault}, 1",
+            "3:",
+            ".pushsection __ex_table, \"a\"",
+            ".balign 8",
+            ".quad 1b, 4b",
+            ".quad 2b, 4b",
+            ".popsection",
+            uaddr = in(reg) uaddr,
+            // Words are compared as `lr.w` loads them, sign-extended.
+            old = in(reg) old as i32 as isize,
+            new = in(reg) new,
+            value = out(reg) value,
+            fault = out(reg) fault,
+        );
+    }
+
+    match fault {
+        0 => Ok(value as u32),
+        _ => Err(OsError::BadPtr),
+    }
+}
+
 extern "C" {
     pub fn __knrl_read_usr_byte(user_src: *const u8, byte_ptr: *const u8) -> u8;
-    pub fn __knrl_read_usr_byte_pc();
-    pub fn __knrl_read_usr_exit();
     pub fn __knrl_write_usr_byte(user_src: *const u8, value: u8) -> u8;
-    pub fn __knrl_write_usr_byte_pc();
-    pub fn __knrl_write_usr_exit();
+    pub fn __knrl_copy_usr(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    pub fn __knrl_strncpy_usr(dst: *mut u8, src: *const u8, len: usize) -> isize;
 }
 
+// Every instruction below that touches user memory has an entry in the
+// exception table, see `trap::extable`. A fault on it resumes at the fixup,
+// which makes the routine report the failure.
 global_asm! {r#"
+    .macro extable insn, fixup
+        .pushsection __ex_table, "a"
+        .balign 8
+        .quad \insn, \fixup
+        .popsection
+    .endm
+
         .section .text
         .globl __knrl_read_usr_byte
-        .globl __knrl_read_usr_exit
-        .globl __knrl_read_usr_byte_pc
 
+    # Reads the byte at a0 into a1. Returns 0, or 1 if a fault happened.
     __knrl_read_usr_byte:
-        mv t1, a1
-        li a1, 0
-    __knrl_read_usr_byte_pc:
-        lb t0, (a0)
-    __knrl_read_usr_exit:
-        # pagefault handler will set a1 if any error occurs
-        sb t0, (t1)
-        mv a0, a1
+    1:  lb t0, (a0)
+        sb t0, (a1)
+        li a0, 0
         ret
+    2:  li a0, 1
+        ret
+        extable 1b, 2b
 
         .globl __knrl_write_usr_byte
-        .globl __knrl_write_usr_exit
-        .globl __knrl_write_usr_byte_pc
 
+    # Writes a1 to the byte at a0. Returns 0, or 1 if a fault happened.
     __knrl_write_usr_byte:
-        mv t1, a1
-        li a1, 0
-    __knrl_write_usr_byte_pc:
-        sb t1, (a0)
-    __knrl_write_usr_exit:
-        # pagefault handler will set a1 if any error occurs
-        mv a0, a1
+    1:  sb a1, (a0)
+        li a0, 0
+        ret
+    2:  li a0, 1
+        ret
+        extable 1b, 2b
+
+        .globl __knrl_copy_usr
+
+    # Copies a2 bytes from a1 to a0. Returns 0, or 1 if a fault happened.
+    __knrl_copy_usr:
+        or t0, a0, a1
+        andi t0, t0, 7
+        bnez t0, 2f
+        # Both are aligned, copy doublewords first.
+    1:  li t1, 8
+        bltu a2, t1, 2f
+    10: ld t0, 0(a1)
+    11: sd t0, 0(a0)
+        addi a0, a0, 8
+        addi a1, a1, 8
+        addi a2, a2, -8
+        j 1b
+    2:  beqz a2, 3f
+    12: lb t0, 0(a1)
+    13: sb t0, 0(a0)
+        addi a0, a0, 1
+        addi a1, a1, 1
+        addi a2, a2, -1
+        j 2b
+    3:  li a0, 0
+        ret
+    4:  li a0, 1
+        ret
+        extable 10b, 4b
+        extable 11b, 4b
+        extable 12b, 4b
+        extable 13b, 4b
+
+        .globl __knrl_strncpy_usr
+
+    # Copies a string from a1 to a0, with its NUL, but at most a2 bytes.
+    # Returns the length of the string, a2 if no NUL is found, or -1 if a
+    # fault happened.
+    __knrl_strncpy_usr:
+        mv t1, a2
+    1:  beqz t1, 2f
+    10: lb t0, 0(a1)
+        sb t0, 0(a0)
+        beqz t0, 2f
+        addi a0, a0, 1
+        addi a1, a1, 1
+        addi t1, t1, -1
+        j 1b
+    2:  sub a0, a2, t1
+        ret
+    3:  li a0, -1
         ret
+        extable 10b, 3b
 "#}
@@ -6,10 +6,13 @@ use core::arch::global_asm;
 use core::fmt::{self, Debug};
 use core::sync::atomic::{AtomicIsize, AtomicU32, Ordering::SeqCst};
 
-use crate::mem::{kalloc, kfree, PageTable, PG_SIZE};
+use crate::mem::slab::Cache;
+use crate::mem::{PageTable, Palloc, PG_SIZE};
 use crate::sbi::interrupt;
+use crate::sync::Lazy;
 use crate::thread::Manager;
 use crate::userproc::UserProc;
+use crate::{bootstack, bootstack_top, Result};
 
 pub const PRI_DEFAULT: u32 = 31;
 pub const PRI_MAX: u32 = 63;
@@ -21,6 +24,13 @@ pub const MAGIC: usize = 0xdeadbeef;
 
 pub type Mutex<T> = crate::sync::Mutex<T, crate::sync::Intr>;
 
+/// A kernel stack, aligned to [`STACK_ALIGN`]
+#[repr(C, align(16))]
+struct Stack([u8; STACK_SIZE]);
+
+/// Kernel stacks of all threads but the initial one
+static STACKS: Lazy<Cache<Stack>> = Lazy::new(|| Cache::new("thread stack"));
+
 /* --------------------------------- Thread --------------------------------- */
 /// All data of a kernel thread
 #[repr(C)]
@@ -31,8 +41,13 @@ pub struct Thread {
     status: Mutex<Status>,
     context: Mutex<Context>,
     pub priority: AtomicU32,
-    pub userproc: Option<UserProc>,
-    pub pagetable: Option<Mutex<PageTable>>,
+    /// The user process, shared by all its threads
+    pub userproc: Option<Arc<UserProc>>,
+    /// The address space, shared by all threads of a user process
+    pub pagetable: Option<Arc<Mutex<PageTable>>>,
+    /// The allocation tag of the thread while it's switched out
+    #[cfg(feature = "mem-trace")]
+    pub(crate) trace_tag: Mutex<&'static str>,
 }
 
 impl Thread {
@@ -41,8 +56,8 @@ impl Thread {
         stack: usize,
         priority: u32,
         entry: usize,
-        userproc: Option<UserProc>,
-        pagetable: Option<PageTable>,
+        userproc: Option<Arc<UserProc>>,
+        pagetable: Option<Arc<Mutex<PageTable>>>,
     ) -> Self {
         /// The next thread's id
         static TID: AtomicIsize = AtomicIsize::new(0);
@@ -55,7 +70,9 @@ impl Thread {
             context: Mutex::new(Context::new(stack, entry)),
             priority: AtomicU32::new(priority),
             userproc,
-            pagetable: pagetable.map(Mutex::new),
+            pagetable,
+            #[cfg(feature = "mem-trace")]
+            trace_tag: Mutex::new(crate::mem::trace::UNTAGGED),
         }
     }
 
@@ -100,20 +117,33 @@ impl Drop for Thread {
         #[cfg(feature = "debug")]
         kprintln!("[THREAD] {:?}'s resources are released", self);
 
-        kfree(self.stack as *mut _, STACK_SIZE, STACK_ALIGN);
-        if let Some(pt) = &self.pagetable {
-            unsafe { pt.lock().destroy() };
+        if self.stack == bootstack as *const () as usize {
+            // The initial thread runs on the boot stack, which isn't from the heap.
+            unsafe { Palloc::insert_range(self.stack, bootstack_top as *const () as usize) };
+        } else {
+            unsafe { STACKS.free(self.stack as *mut Stack) };
+        }
+        // The last thread of a user process takes the address space away.
+        if let Some(pt) = self.pagetable.take() {
+            destroy(pt);
         }
     }
 }
 
+/// Destroys `pagetable` if no other thread holds it.
+fn destroy(pagetable: Arc<Mutex<PageTable>>) {
+    if let Ok(pagetable) = Arc::try_unwrap(pagetable) {
+        unsafe { pagetable.lock().destroy() };
+    }
+}
+
 /* --------------------------------- BUILDER -------------------------------- */
 pub struct Builder {
     priority: u32,
     name: &'static str,
     function: usize,
-    userproc: Option<UserProc>,
-    pagetable: Option<PageTable>,
+    userproc: Option<Arc<UserProc>>,
+    pagetable: Option<Arc<Mutex<PageTable>>>,
 }
 
 impl Builder {
@@ -144,29 +174,54 @@ impl Builder {
     }
 
     pub fn pagetable(mut self, pagetable: PageTable) -> Self {
-        self.pagetable = Some(pagetable);
+        self.pagetable = Some(Arc::new(Mutex::new(pagetable)));
         self
     }
 
     pub fn userproc(mut self, userproc: UserProc) -> Self {
-        self.userproc = Some(userproc);
+        self.userproc = Some(Arc::new(userproc));
+        self
+    }
+
+    /// Runs the thread in the user process and the address space of `thread`.
+    pub fn sibling_of(mut self, thread: &Thread) -> Self {
+        self.userproc = thread.userproc.clone();
+        self.pagetable = thread.pagetable.clone();
         self
     }
 
-    pub fn build(self) -> Arc<Thread> {
-        let stack = kalloc(STACK_SIZE, STACK_ALIGN) as usize;
+    /// Builds the thread without registering it.
+    ///
+    /// ## Errors
+    /// [`OutOfMemory`](crate::OsError::OutOfMemory) if no kernel stack can be allocated. The
+    /// function, the page table and the user process are dropped then, unless
+    /// other threads share them.
+    pub fn build(self) -> Result<Arc<Thread>> {
+        #[cfg(feature = "mem-trace")]
+        let _tag = crate::mem::trace::Tag::new("thread");
+
+        let stack = match STACKS.alloc() {
+            Ok(stack) => stack as usize,
+            Err(e) => {
+                unsafe { drop(Box::from_raw(self.function as *mut Box<dyn FnOnce()>)) };
+                if let Some(pagetable) = self.pagetable {
+                    destroy(pagetable);
+                }
+                return Err(e);
+            }
+        };
 
         // Put magic number at the bottom of the stack.
         unsafe { (stack as *mut usize).write(MAGIC) };
 
-        Arc::new(Thread::new(
+        Ok(Arc::new(Thread::new(
             self.name,
             stack,
             self.priority,
             self.function,
             self.userproc,
             self.pagetable,
-        ))
+        )))
     }
 
     /// Spawns a kernel thread and registers it to the [`Manager`].
@@ -174,8 +229,16 @@ impl Builder {
     /// `userproc` and `pagetable` have to be set properly.
     ///
     /// Note that this function CANNOT be called during [`Manager`]'s initialization.
+    ///
+    /// Panics if memory is exhausted, see [`Builder::try_spawn`].
     pub fn spawn(self) -> Arc<Thread> {
-        let new_thread = self.build();
+        self.try_spawn().expect("no memory for a new thread")
+    }
+
+    /// Like [`Builder::spawn`], but fails with [`OutOfMemory`](crate::OsError::OutOfMemory)
+    /// instead of panicking.
+    pub fn try_spawn(self) -> Result<Arc<Thread>> {
+        let new_thread = self.build()?;
 
         #[cfg(feature = "debug")]
         kprintln!("[THREAD] create {:?}", new_thread);
@@ -183,7 +246,7 @@ impl Builder {
         Manager::get().register(new_thread.clone());
 
         // Off you go
-        new_thread
+        Ok(new_thread)
     }
 }
 
@@ -43,7 +43,8 @@ impl Manager {
             })
             .name("Idle")
             .priority(PRI_MIN)
-            .build();
+            .build()
+            .expect("no memory for the idle thread");
             manager.register(idle);
 
             manager
@@ -52,6 +53,21 @@ impl Manager {
         &TMANAGER
     }
 
+    /// Finds an alive thread by its id
+    pub fn find(&self, tid: isize) -> Option<Arc<Thread>> {
+        self.all.lock().iter().find(|t| t.id() == tid).cloned()
+    }
+
+    /// Number of alive and not yet destroyed threads, including the idle one
+    pub fn alive(&self) -> usize {
+        self.all.lock().len()
+    }
+
+    /// Calls `f` on every alive and not yet destroyed thread
+    pub fn for_each(&self, f: impl FnMut(&Arc<Thread>)) {
+        self.all.lock().iter().for_each(f)
+    }
+
     pub(super) fn register(&self, thread: Arc<Thread>) {
         // Register it into the scheduler
         self.scheduler.lock().register(thread.clone());
@@ -96,6 +112,9 @@ impl Manager {
             let old_ctx = previous.context();
             let new_ctx = self.current.lock().context();
 
+            #[cfg(feature = "mem-trace")]
+            crate::mem::trace::switch(&previous, &self.current.lock());
+
             // WARNING: This function call may not return, so don't expect any value to be dropped.
 
             unsafe { switch::switch(Arc::into_raw(previous).cast(), old_ctx, new_ctx) }
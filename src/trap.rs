@@ -1,12 +1,14 @@
 //! Trap handler
 //!
 
+mod extable;
 mod pagefault;
 mod syscall;
 
 use crate::device::{plic, virtio};
 use crate::sbi;
 use crate::thread;
+use crate::userproc::{self, signal};
 use core::arch;
 
 use riscv::register::scause::{Exception::*, Interrupt::*, Trap::*};
@@ -18,7 +20,7 @@ use riscv::register::*;
 /* -------------------------------------------------------------------------- */
 
 #[repr(C)]
-
+#[derive(Clone)]
 /// Trap context
 pub struct Frame {
     /// General regs[0..31].
@@ -45,6 +47,14 @@ pub extern "C" fn trap_handler(frame: &mut Frame) {
     // Force to use kernel handler. Rely on trap_exit_k to restore the proper one.
     set_strap_entry();
 
+    // Whatever the handler runs, possibly other threads, must not reach user
+    // memory by accident. The trapped code gets its `SUM` back on return.
+    unsafe { clear_sum() };
+
+    if frame.sstatus.spp() == SPP::User {
+        userproc::save_sp(frame.x[2]);
+    }
+
     let scause = scause::read().cause();
     let stval = stval::read();
 
@@ -65,11 +75,15 @@ pub extern "C" fn trap_handler(frame: &mut Frame) {
             unsafe { riscv::register::sstatus::set_sie() };
             // Increase sepc by 1 to skip ecall.
             frame.sepc += 4;
-            frame.x[10] = syscall::syscall_handler(id, args) as usize;
+            frame.x[10] = syscall::syscall_handler(id, args, frame) as usize;
         }
 
         Interrupt(SupervisorTimer) => {
             sbi::timer::tick();
+            #[cfg(feature = "mem-poison")]
+            if sbi::timer::timer_ticks() % crate::mem::malloc::poison::CHECK_INTERVAL == 0 {
+                crate::mem::malloc::Heap::get().check();
+            }
             unsafe { riscv::register::sstatus::set_sie() };
             thread::schedule();
         }
@@ -89,17 +103,35 @@ pub extern "C" fn trap_handler(frame: &mut Frame) {
             plic::write_completion(id);
         },
 
-        Exception(InstructionFault) | Exception(IllegalInstruction) => {
-            // TODO: kill user process but not panic kernel
-            panic!("Instruction failure");
-        }
-
         Exception(f @ LoadPageFault)
         | Exception(f @ StorePageFault)
         | Exception(f @ InstructionPageFault) => {
             pagefault::handler(frame, f, stval);
         }
 
+        // Any other exception of user code is the fault of the process.
+        Exception(f) if frame.sstatus.spp() == SPP::User => {
+            kprintln!(
+                "{:?} at {:#x} in user thread {}.",
+                f,
+                frame.sepc,
+                thread::current().name()
+            );
+            unsafe { riscv::register::sstatus::set_sie() };
+            signal::force(match f {
+                IllegalInstruction => signal::SIGILL,
+                Breakpoint => signal::SIGTRAP,
+                InstructionMisaligned | StoreMisaligned => signal::SIGBUS,
+                // `riscv` has no variant for misaligned loads, cause 4.
+                scause::Exception::Unknown if scause::read().code() == 4 => signal::SIGBUS,
+                _ => signal::SIGSEGV,
+            });
+        }
+
+        Exception(InstructionFault) | Exception(IllegalInstruction) => {
+            panic!("Instruction failure at {:#x}", frame.sepc);
+        }
+
         _ => {
             unimplemented!(
                 "Unsupported trap {:?}, stval={:#x}, sepc={:#x}",
@@ -110,6 +142,11 @@ pub extern "C" fn trap_handler(frame: &mut Frame) {
         }
     }
 
+    // Signals raised meanwhile are taken on the way back to user mode.
+    if frame.sstatus.spp() == SPP::User {
+        userproc::signal::deliver(frame);
+    }
+
     #[cfg(feature = "debug")]
     kprintln!("[TRAP] exit");
 }
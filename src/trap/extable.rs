@@ -0,0 +1,39 @@
+//! Exception table
+//!
+//! Routines that access user memory on behalf of the kernel may fault on a
+//! bad user pointer. Each instruction of theirs that might is listed in the
+//! `__ex_table` section, together with the address to resume at instead: a
+//! fixup that makes the routine fail gracefully. The linker gathers the
+//! entries of all routines between `__ex_table_start` and `__ex_table_end`.
+
+/// An entry as laid out by the `extable` assembler macro
+#[repr(C)]
+struct Entry {
+    insn: usize,
+    fixup: usize,
+}
+
+extern "C" {
+    fn __ex_table_start();
+    fn __ex_table_end();
+}
+
+fn entries() -> &'static [Entry] {
+    let start = __ex_table_start as *const () as usize;
+    let end = __ex_table_end as *const () as usize;
+    unsafe {
+        core::slice::from_raw_parts(
+            start as *const Entry,
+            (end - start) / core::mem::size_of::<Entry>(),
+        )
+    }
+}
+
+/// The address to resume at after a fault of the instruction at `pc`, if
+/// the fault can be recovered from.
+pub fn fixup(pc: usize) -> Option<usize> {
+    entries()
+        .iter()
+        .find(|entry| entry.insn == pc)
+        .map(|entry| entry.fixup)
+}
@@ -1,9 +1,5 @@
-use crate::mem::userbuf::{
-    __knrl_read_usr_byte_pc, __knrl_read_usr_exit, __knrl_write_usr_byte_pc, __knrl_write_usr_exit,
-};
-use crate::mem::PageTable;
-use crate::thread::{self};
-use crate::trap::Frame;
+use crate::mem::{in_kernel_space, PageTable};
+use crate::trap::{extable, Frame};
 use crate::userproc;
 
 use riscv::register::scause::Exception::{self, *};
@@ -20,8 +16,30 @@ pub fn handler(frame: &mut Frame, fault: Exception, addr: usize) {
         }
     };
 
+    // The kernel may only touch user memory with `SUM` set, in the routines
+    // of `userbuf`. Anything else is a bug, like a fault on SMAP.
+    if privilege == SPP::Supervisor && !in_kernel_space(addr) && !frame.sstatus.sum() {
+        panic!(
+            "Kernel accessed user memory at {:#x} from {:#x}",
+            addr, frame.sepc
+        );
+    }
+
     unsafe { sstatus::set_sie() };
 
+    // Pages of user programs are loaded lazily, and shared pages of forked
+    // processes are copied on write. Both user code and kernel code accessing
+    // user memory may be the first to touch a page.
+    if !in_kernel_space(addr) {
+        let write = fault == StorePageFault;
+        if !present && userproc::load_page(addr, write) {
+            return;
+        }
+        if present && write && userproc::copy_on_write(addr) {
+            return;
+        }
+    }
+
     kprintln!(
         "Page fault at {:#x}: {} error {} page in {} context.",
         addr,
@@ -39,25 +57,11 @@ pub fn handler(frame: &mut Frame, fault: Exception, addr: usize) {
     );
 
     match privilege {
-        SPP::Supervisor => {
-            if frame.sepc == __knrl_read_usr_byte_pc as _ {
-                // Failed to read user byte from kernel space when trap in pagefault
-                frame.x[11] = 1; // set a1 to non-zero
-                frame.sepc = __knrl_read_usr_exit as _;
-            } else if frame.sepc == __knrl_write_usr_byte_pc as _ {
-                // Failed to write user byte from kernel space when trap in pagefault
-                frame.x[11] = 1; // set a1 to non-zero
-                frame.sepc = __knrl_write_usr_exit as _;
-            } else {
-                panic!("Kernel page fault");
-            }
-        }
-        SPP::User => {
-            kprintln!(
-                "User thread {} dying due to page fault.",
-                thread::current().name()
-            );
-            userproc::exit(-1);
-        }
+        SPP::Supervisor => match extable::fixup(frame.sepc) {
+            // A routine accessing user memory hit a bad pointer, let it fail.
+            Some(fixup) => frame.sepc = fixup,
+            None => panic!("Kernel page fault"),
+        },
+        SPP::User => userproc::signal::force(userproc::signal::SIGSEGV),
     }
 }
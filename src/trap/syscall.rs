@@ -3,6 +3,10 @@
 
 #![allow(dead_code)]
 
+use crate::thread;
+use crate::trap::Frame;
+use crate::userproc;
+
 /* -------------------------------------------------------------------------- */
 /*                               SYSCALL NUMBER                               */
 /* -------------------------------------------------------------------------- */
@@ -19,8 +23,62 @@ const SYS_SEEK: usize = 9;
 const SYS_TELL: usize = 10;
 const SYS_CLOSE: usize = 11;
 const SYS_FSTAT: usize = 12;
+const SYS_MMAP: usize = 13;
+const SYS_MUNMAP: usize = 14;
+const SYS_FORK: usize = 17;
+const SYS_YIELD: usize = 18;
+const SYS_SHMAT: usize = 19;
+const SYS_SHMDT: usize = 20;
+const SYS_SBRK: usize = 21;
+const SYS_BRK: usize = 22;
+const SYS_MEMMAP: usize = 23;
+const SYS_PIPE: usize = 24;
+const SYS_DUP: usize = 25;
+const SYS_DUP2: usize = 26;
+const SYS_KILL: usize = 27;
+const SYS_SIGACTION: usize = 28;
+const SYS_SIGRETURN: usize = 29;
+const SYS_THREAD_CREATE: usize = 30;
+const SYS_THREAD_EXIT: usize = 31;
+const SYS_THREAD_JOIN: usize = 32;
 
-pub fn syscall_handler(_id: usize, _args: [usize; 3]) -> isize {
-    // TODO: LAB2 impl
-    -1
+pub fn syscall_handler(id: usize, args: [usize; 3], frame: &mut Frame) -> isize {
+    match id {
+        SYS_EXIT => userproc::exit(args[0] as _),
+        SYS_EXEC => userproc::exec(args[0] as _, args[1] as _).unwrap_or(-1),
+        SYS_WAIT => userproc::wait(args[0] as _).unwrap_or(-1),
+        SYS_OPEN => userproc::open(args[0] as _, args[1]).unwrap_or(-1),
+        SYS_READ => userproc::read(args[0] as _, args[1] as _, args[2]).map_or(-1, |n| n as isize),
+        SYS_WRITE => {
+            userproc::write(args[0] as _, args[1] as _, args[2]).map_or(-1, |n| n as isize)
+        }
+        SYS_CLOSE => userproc::close(args[0] as _).map_or(-1, |_| 0),
+        SYS_PIPE => userproc::pipe(args[0] as _).map_or(-1, |_| 0),
+        SYS_DUP => userproc::dup(args[0] as _).unwrap_or(-1),
+        SYS_DUP2 => userproc::dup2(args[0] as _, args[1] as _).unwrap_or(-1),
+        SYS_MMAP => userproc::mmap(args[0] as _, args[1]).unwrap_or(-1),
+        SYS_MUNMAP => userproc::munmap(args[0] as _).map_or(-1, |_| 0),
+        SYS_FORK => userproc::fork(frame),
+        SYS_SHMAT => {
+            userproc::shmat(args[0] as _, args[1], args[2]).map_or(-1, |addr| addr as isize)
+        }
+        SYS_SHMDT => userproc::shmdt(args[0]).map_or(-1, |_| 0),
+        SYS_SBRK => userproc::sbrk(args[0] as _).map_or(-1, |brk| brk as isize),
+        SYS_BRK => userproc::brk(args[0]).map_or(-1, |_| 0),
+        SYS_KILL => userproc::kill(args[0] as _, args[1]).map_or(-1, |_| 0),
+        SYS_SIGACTION => {
+            userproc::sigaction(args[0], args[1], args[2]).map_or(-1, |old| old as isize)
+        }
+        SYS_SIGRETURN => userproc::sigreturn(frame),
+        SYS_THREAD_CREATE => userproc::thread_create(args[0], args[1], args[2]).unwrap_or(-1),
+        SYS_THREAD_EXIT => userproc::thread_exit(args[0] as _),
+        SYS_THREAD_JOIN => userproc::thread_join(args[0] as _).unwrap_or(-1),
+        SYS_MEMMAP => userproc::memmap(thread::current().id()).map_or(-1, |_| 0),
+        SYS_YIELD => {
+            thread::schedule();
+            0
+        }
+        // TODO: LAB2 impl
+        _ => -1,
+    }
 }
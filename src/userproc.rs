@@ -1,37 +1,174 @@
 //! User process.
 //!
 
+mod brk;
+mod evict;
+pub mod fdt;
 mod load;
+mod memmap;
+mod mmap;
+mod shm;
+pub mod signal;
+pub mod spt;
+mod uthread;
+mod wait;
+
+pub use self::brk::{brk, sbrk};
+pub use self::evict::{reclaim, reclaim_others};
+pub use self::fdt::{close, dup, dup2, open, pipe, read, write};
+pub use self::memmap::memmap;
+pub use self::mmap::{mmap, munmap, MapId};
+pub use self::shm::{shmat, shmdt, ShmKey};
+pub use self::signal::{kill, sigaction, sigreturn};
+pub use self::uthread::{thread_create, thread_exit, thread_join};
+pub use self::wait::wait;
 
 use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
 use core::arch::asm;
-use core::mem::MaybeUninit;
+use core::mem::{size_of, MaybeUninit};
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering::SeqCst};
 use riscv::register::sstatus;
 
-use crate::fs::File;
-use crate::mem::pagetable::KernelPgTable;
-use crate::thread;
+use crate::fs::disk::DISKFS;
+use crate::fs::{File, FileSys};
+use crate::mem::pagetable::{KernelPgTable, PTEFlags, PageTable};
+use crate::mem::palloc::UserPool;
+use crate::mem::userbuf::{copy_from_user, strncpy_from_user};
+use crate::mem::{PageAlign, PhysAddr, PG_SIZE};
+use crate::sync::Mutex;
+use crate::thread::{self, STACK_TOP};
 use crate::trap::{trap_exit_u, Frame};
+use crate::{OsError, Result};
+
+use self::brk::Break;
+use self::fdt::FdTable;
+use self::load::Segment;
+use self::mmap::MmapTable;
+use self::shm::ShmTable;
+use self::signal::Signals;
+use self::spt::SupplementalPageTable;
+use self::uthread::ThreadGroup;
+use self::wait::Exit;
 
 pub struct UserProc {
     #[allow(dead_code)]
     bin: File,
+    /// Loaded segments of the executable.
+    segments: Vec<Segment>,
+    /// Pages that are brought in on demand.
+    pub spt: Mutex<SupplementalPageTable>,
+    /// Open files.
+    pub fdt: Mutex<FdTable>,
+    /// Memory-mapped files.
+    mmaps: Mutex<MmapTable>,
+    /// Attached shared memory segments.
+    shm: Mutex<ShmTable>,
+    /// The heap, which ends at the program break.
+    brk: Mutex<Break>,
+    /// Pending signals and their actions.
+    signals: Mutex<Signals>,
+    /// Threads running in the process.
+    threads: ThreadGroup,
+    /// Stack pointer of the initial thread at its latest trap from user mode.
+    sp: AtomicUsize,
+    /// The exit value, shared with the parent.
+    exit: Arc<Exit>,
 }
 
 impl UserProc {
-    pub fn new(file: File) -> Self {
-        Self { bin: file }
+    /// Creates a process whose heap starts at `brk`.
+    pub fn new(file: File, spt: SupplementalPageTable, segments: Vec<Segment>, brk: usize) -> Self {
+        Self {
+            bin: file,
+            segments,
+            spt: Mutex::new(spt),
+            fdt: Mutex::new(FdTable::new()),
+            mmaps: Mutex::new(MmapTable::new()),
+            shm: Mutex::new(ShmTable::new()),
+            brk: Mutex::new(Break::new(brk)),
+            signals: Mutex::new(Signals::new()),
+            threads: ThreadGroup::new(),
+            sp: AtomicUsize::new(STACK_TOP),
+            exit: Arc::new(Exit::new()),
+        }
+    }
+
+    /// Duplicates the states of this process for a forked child, which runs
+    /// only a copy of the forking thread.
+    fn fork(&self) -> Self {
+        Self {
+            bin: self.bin.clone(),
+            segments: self.segments.clone(),
+            spt: Mutex::new(self.spt.lock().clone()),
+            fdt: Mutex::new(self.fdt.lock().clone()),
+            // Mappings are not inherited. The child keeps a private copy
+            // of the mapped pages, which are never written back.
+            mmaps: Mutex::new(MmapTable::new()),
+            // Shared memory stays shared with the child.
+            shm: Mutex::new(self.shm.lock().clone()),
+            brk: Mutex::new(*self.brk.lock()),
+            signals: Mutex::new(self.signals.lock().fork()),
+            threads: ThreadGroup::new(),
+            sp: AtomicUsize::new(self.sp.load(SeqCst)),
+            exit: Arc::new(Exit::new()),
+        }
+    }
+
+    /// Records `sp` if it's on the stack of the initial thread. Other threads
+    /// run on stacks of their own, which don't grow.
+    fn set_sp(&self, sp: usize) {
+        if (STACK_TOP - STACK_LIMIT..=STACK_TOP).contains(&sp) {
+            self.sp.store(sp, SeqCst);
+        }
+    }
+
+    /// Extends the user stack to the page containing `addr`, if `addr` lies
+    /// above the stack pointer and within [`STACK_LIMIT`] below [`STACK_TOP`].
+    fn grow_stack(&self, addr: usize, pagetable: &thread::Mutex<PageTable>) -> bool {
+        if addr < self.sp.load(SeqCst) || !(STACK_TOP - STACK_LIMIT..STACK_TOP).contains(&addr) {
+            return false;
+        }
+
+        let frame = match unsafe { UserPool::try_alloc_pages(1) } {
+            Ok(frame) => frame,
+            Err(_) => return false,
+        };
+        unsafe { frame.write_bytes(0, PG_SIZE) };
+
+        let flags = PTEFlags::V | PTEFlags::R | PTEFlags::W | PTEFlags::U;
+        let mut pagetable = pagetable.lock();
+        if pagetable
+            .try_map(PhysAddr::from(frame), addr.floor(), PG_SIZE, flags)
+            .is_err()
+        {
+            unsafe { UserPool::dealloc_pages(frame, 1) };
+            return false;
+        }
+        pagetable.flush_tlb(addr.floor());
+
+        true
     }
 }
 
-/// Execute an object file with arguments.
+/// The maximum size of a user stack. Accesses to the stack grow it on demand
+/// until it reaches this size.
+pub const STACK_LIMIT: usize = 8 << 20;
+
+/// Execute an object file with arguments and an environment. The program
+/// finds them on its stack, and also gets `argc`, `argv` and `envp` in `a0`,
+/// `a1` and `a2`. The current thread may [`wait`] for the process.
 ///
 /// ## Return
-/// - `-1`: On error.
+/// - `-1`: On error, such as `argv` and `envp` being too long.
 /// - `tid`: Tid of the newly spawned thread.
-#[allow(unused_variables)]
-pub fn execute(mut file: File, argv: Vec<String>) -> isize {
+pub fn execute(mut file: File, argv: Vec<String>, envp: Vec<String>) -> isize {
+    #[cfg(feature = "mem-trace")]
+    let _tag = crate::mem::trace::Tag::new("userproc");
+
     #[cfg(feature = "debug")]
     kprintln!(
         "[PROCESS] Kernel thread {} prepare to execute a process with args {:?}",
@@ -42,9 +179,13 @@ pub fn execute(mut file: File, argv: Vec<String>) -> isize {
     // It only copies L2 pagetable. This approach allows the new thread
     // to access kernel code and data during syscall without the need to
     // switch pagetables.
-    let mut pt = KernelPgTable::clone();
+    let mut pt = match KernelPgTable::try_clone() {
+        Ok(pt) => pt,
+        Err(_) => return -1,
+    };
+    let mut spt = SupplementalPageTable::new();
 
-    let exec_info = match load::load_executable(&mut file, &mut pt) {
+    let exec_info = match load::load_executable(&mut file, &mut pt, &mut spt, &argv, &envp) {
         Ok(x) => x,
         Err(_) => unsafe {
             pt.destroy();
@@ -56,35 +197,214 @@ pub fn execute(mut file: File, argv: Vec<String>) -> isize {
     let mut frame = unsafe { MaybeUninit::<Frame>::zeroed().assume_init() };
     frame.sepc = exec_info.entry_point;
     frame.x[2] = exec_info.init_sp;
+    frame.x[10] = argv.len();
+    frame.x[11] = exec_info.argv;
+    frame.x[12] = exec_info.envp;
 
     // Here the new process will be created.
-    let userproc = UserProc::new(file);
-
-    // TODO: (Lab2) Pass arguments to user program
+    let userproc = UserProc::new(file, spt, exec_info.segments, exec_info.brk);
+    let exit = userproc.exit.clone();
 
     thread::Builder::new(move || start(frame))
         .pagetable(pt)
         .userproc(userproc)
-        .spawn()
-        .id()
+        .try_spawn()
+        .map_or(-1, |thread| {
+            wait::adopt(thread.id(), exit);
+            thread.id()
+        })
 }
 
-/// Exits a process.
+/// Executes the program at `path` in a new process, with arguments `argv`.
+/// Both are in user memory, and `argv` is an array of strings ended by a
+/// null pointer.
+///
+/// ## Return
+/// Tid of the new process.
+///
+/// ## Errors
+/// - [`OsError::BadPtr`]: `path` or `argv` can't be read.
+/// - [`OsError::ArgumentTooLong`]: `path` doesn't fit in [`PATH_MAX`](fdt::PATH_MAX)
+///   bytes, or `argv` doesn't fit in a page.
+/// - [`OsError::NoSuchFile`]: there is no such file.
+/// - [`OsError::UserError`]: the file can't be loaded.
+pub fn exec(path: *const u8, argv: *const usize) -> Result<isize> {
+    let mut buf = [0u8; fdt::PATH_MAX];
+    let file = DISKFS.open(fdt::read_path(&mut buf, path)?.into())?;
+    let argv = read_args(argv)?;
+
+    match execute(file, argv, Vec::new()) {
+        -1 => Err(OsError::UserError),
+        tid => Ok(tid),
+    }
+}
+
+/// Copies the strings of `argv` in user memory. A null `argv` holds none.
+fn read_args(mut argv: *const usize) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    if argv.is_null() {
+        return Ok(args);
+    }
+
+    // The strings and the pointers to them must fit in the first stack page.
+    let mut buf = vec![0u8; PG_SIZE];
+    let mut total = 0;
+    loop {
+        let mut ptr = [0u8; size_of::<usize>()];
+        copy_from_user(&mut ptr, argv as usize)?;
+        let ptr = usize::from_ne_bytes(ptr);
+        if ptr == 0 {
+            return Ok(args);
+        }
+
+        let len = strncpy_from_user(&mut buf, ptr)?;
+        total += len + 1 + size_of::<usize>();
+        if total > PG_SIZE {
+            return Err(OsError::ArgumentTooLong);
+        }
+        let arg = str::from_utf8(&buf[..len]).map_err(|_| OsError::CstrFormatErr)?;
+        args.push(String::from(arg));
+
+        argv = argv.wrapping_add(1);
+    }
+}
+
+/// Clones the current process. The child shares all user frames with the
+/// parent copy-on-write, and returns from the same syscall with `0`. The
+/// current thread may [`wait`] for it.
+///
+/// ## Return
+/// - `-1`: On error.
+/// - `tid`: Tid of the child thread.
+pub fn fork(frame: &Frame) -> isize {
+    #[cfg(feature = "mem-trace")]
+    let _tag = crate::mem::trace::Tag::new("userproc");
+
+    let current = thread::current();
+
+    let (userproc, pagetable) = match (current.userproc.as_ref(), current.pagetable.as_ref()) {
+        (Some(userproc), Some(pagetable)) => {
+            let shm = userproc.shm.lock();
+            let child = match pagetable.lock().fork(|va| shm.contains(va)) {
+                Ok(child) => child,
+                Err(_) => return -1,
+            };
+            drop(shm);
+            (userproc.fork(), child)
+        }
+        _ => return -1,
+    };
+
+    let mut frame = frame.clone();
+    frame.x[10] = 0;
+    let exit = userproc.exit.clone();
+
+    thread::Builder::new(move || start(frame))
+        .name(current.name())
+        .pagetable(pagetable)
+        .userproc(userproc)
+        .try_spawn()
+        .map_or(-1, |thread| {
+            wait::adopt(thread.id(), exit);
+            thread.id()
+        })
+}
+
+/// Exits a process. Its other threads leave on their way back to user mode.
 ///
 /// Panic if the current thread doesn't own a user process.
-pub fn exit(_value: isize) -> ! {
-    // TODO: Lab2.
+pub fn exit(value: isize) -> ! {
+    leave(value, true)
+}
+
+/// Ends the current thread, and the process with it if `kill` is set or it's
+/// the last thread.
+fn leave(value: isize, kill: bool) -> ! {
+    {
+        let current = thread::current();
+        let userproc = current.userproc.as_ref().unwrap();
+        let pagetable = current.pagetable.as_ref().unwrap();
+
+        let killed = kill && userproc.threads.kill();
+        let last = userproc.threads.leave(current.id(), value);
+
+        if killed || last {
+            // Write back the mapped files before the address space goes away.
+            mmap::munmap_all(userproc, pagetable);
+            shm::shmdt_all(userproc, pagetable);
+
+            // Readers of pipes this process writes to may be waiting for the end.
+            *userproc.fdt.lock() = FdTable::new();
+
+            userproc.exit.set(value);
+        }
+
+        wait::orphan(current.id());
+    }
+
     thread::exit();
 }
 
-/// Waits for a child thread, which must own a user process.
+/// Brings in the page of the current process that contains `addr`, or grows
+/// the user stack to cover it.
+///
+/// ## Return
+/// - `true`: the page is resident now, the faulting access can be retried.
+/// - `false`: `addr` is not a valid page of the current process, or `write`
+///   is set but the page is read-only.
+pub fn load_page(addr: usize, write: bool) -> bool {
+    let current = thread::current();
+
+    match (current.userproc.as_ref(), current.pagetable.as_ref()) {
+        (Some(userproc), Some(pagetable)) => {
+            if userproc.spt.lock().load(addr, write, pagetable).is_ok() {
+                evict::track(pagetable, addr.floor());
+                return true;
+            }
+            userproc.grow_stack(addr, pagetable)
+        }
+        _ => false,
+    }
+}
+
+/// Records the user stack pointer of the current process when it traps into
+/// the kernel. Kernel code accessing user memory on behalf of the process
+/// relies on it to tell stack accesses from wild ones.
+pub fn save_sp(sp: usize) {
+    if let Some(userproc) = thread::current().userproc.as_ref() {
+        userproc.set_sp(sp);
+    }
+}
+
+/// Gives the current process a private copy of the copy-on-write page
+/// that contains `addr`.
 ///
 /// ## Return
-/// - `Some(exit_value)`
-/// - `None`: if tid was not created by the current thread.
-pub fn wait(_tid: isize) -> Option<isize> {
-    // TODO: Lab2.
-    Some(-1)
+/// `false` if `addr` isn't mapped copy-on-write in the current process, or
+/// no frame is left for the copy.
+pub fn copy_on_write(addr: usize) -> bool {
+    let current = thread::current();
+    let pagetable = match current.pagetable.as_ref() {
+        Some(pagetable) => pagetable,
+        None => return false,
+    };
+
+    let is_cow = pagetable
+        .lock()
+        .get_pte(addr)
+        .map_or(false, |entry| entry.is_valid() && entry.is_cow());
+    if !is_cow {
+        return false;
+    }
+
+    // Allocating may evict pages of this process, so the page table must not
+    // be locked meanwhile. Copy-on-write pages are never evicted.
+    let copy = match unsafe { UserPool::try_alloc_pages(1) } {
+        Ok(copy) => copy,
+        Err(_) => return false,
+    };
+    let copied = unsafe { pagetable.lock().copy_on_write(addr, copy) };
+    copied
 }
 
 /// Initializes a user process in current thread.
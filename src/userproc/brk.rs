@@ -0,0 +1,108 @@
+//! Program break.
+//!
+//! The heap of a user process starts right after its highest loaded segment
+//! and ends at the program break. Pages covered by the break are recorded in
+//! the supplemental page table as zeroed pages, so they are only allocated on
+//! the first access. Pages left behind by a shrinking break are unmapped and
+//! their frames freed at once.
+
+use crate::mem::palloc::UserPool;
+use crate::mem::{PTEFlags, PageAlign, PageTable, PG_SIZE};
+use crate::thread::{self, Thread};
+use crate::userproc::mmap::check_region;
+use crate::userproc::spt::{Backing, Page};
+use crate::userproc::UserProc;
+use crate::{OsError, Result};
+
+/// The heap of a user process, from `start` to the program break `end`.
+#[derive(Clone, Copy)]
+pub struct Break {
+    start: usize,
+    end: usize,
+}
+
+impl Break {
+    pub fn new(start: usize) -> Self {
+        Self { start, end: start }
+    }
+
+    /// The start of the heap and the program break
+    pub fn range(&self) -> (usize, usize) {
+        (self.start, self.end)
+    }
+}
+
+/// Moves the program break of the current process by `increment` bytes.
+///
+/// ## Return
+/// The previous program break.
+pub fn sbrk(increment: isize) -> Result<usize> {
+    let current = thread::current();
+    let (userproc, pagetable) = process(&current)?;
+
+    // Threads of the process may move the break at the same time.
+    let mut heap = userproc.brk.lock();
+    let old = heap.end;
+    let new = old
+        .checked_add_signed(increment)
+        .ok_or(OsError::UserError)?;
+    set(userproc, pagetable, &mut heap, new)?;
+
+    Ok(old)
+}
+
+/// Sets the program break of the current process to `addr`.
+///
+/// ## Errors
+/// - [`OsError::UserError`]: `addr` is below the start of the heap.
+/// - [`OsError::BadPtr`]: the heap would run into other pages of the process,
+///   the region reserved for the user stack, or kernel space.
+pub fn brk(addr: usize) -> Result<()> {
+    let current = thread::current();
+    let (userproc, pagetable) = process(&current)?;
+
+    let mut heap = userproc.brk.lock();
+    set(userproc, pagetable, &mut heap, addr)
+}
+
+fn process(thread: &Thread) -> Result<(&UserProc, &thread::Mutex<PageTable>)> {
+    match (thread.userproc.as_ref(), thread.pagetable.as_ref()) {
+        (Some(userproc), Some(pagetable)) => Ok((userproc, pagetable)),
+        _ => Err(OsError::UserError),
+    }
+}
+
+/// Moves the break of `heap`, which is locked in `userproc`, to `addr`.
+fn set(
+    userproc: &UserProc,
+    pagetable: &thread::Mutex<PageTable>,
+    heap: &mut Break,
+    addr: usize,
+) -> Result<()> {
+    if addr < heap.start {
+        return Err(OsError::UserError);
+    }
+
+    // The page the heap starts in belongs to the highest segment.
+    let (old_top, new_top) = (heap.end.ceil(), addr.ceil());
+    let mut spt = userproc.spt.lock();
+
+    if new_top > old_top {
+        check_region(&spt, pagetable, old_top, new_top - old_top)?;
+
+        let flags = PTEFlags::V | PTEFlags::U | PTEFlags::R | PTEFlags::W;
+        for va in (old_top..new_top).step_by(PG_SIZE) {
+            spt.insert(va, Page::new(Backing::Zero, flags));
+        }
+    }
+
+    for va in (new_top..old_top).step_by(PG_SIZE) {
+        spt.remove(va);
+        if let Some(entry) = pagetable.lock().unmap(va) {
+            unsafe { UserPool::dealloc_pages(entry.pa().into_va() as *mut u8, 1) };
+        }
+    }
+
+    heap.end = addr;
+    Ok(())
+}
@@ -0,0 +1,123 @@
+//! Eviction of user pages.
+//!
+//! A page brought in from the supplemental page table is marked with
+//! [`PTEFlags::SPT`]. As long as it stays clean, its frame can be taken away at
+//! any time, since the page is brought in again on the next access. Such pages
+//! are remembered in the order they were loaded, and the oldest ones are
+//! evicted first when frames run short.
+
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use core::cmp::max;
+use core::mem;
+
+use crate::mem::palloc::UserPool;
+use crate::mem::{PTEFlags, PageTable};
+use crate::sync::Lazy;
+use crate::thread::{self, Mutex};
+
+/// Pages that may be resident, with the page table that maps them.
+type Resident = VecDeque<(Weak<Mutex<PageTable>>, usize)>;
+
+/// Only [`track`] adds pages, and nothing may allocate while the lock is held.
+static RESIDENT: Lazy<Mutex<Resident>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+
+/// Entries the list has room for once it first grows
+const MIN_CAPACITY: usize = 64;
+
+/// Remembers that the page at `va` of `pagetable` has been brought in.
+pub(super) fn track(pagetable: &Arc<Mutex<PageTable>>, va: usize) {
+    let mut resident = RESIDENT.lock();
+
+    // Forget pages of exited processes at the front along the way.
+    while resident
+        .front()
+        .map_or(false, |(owner, _)| owner.strong_count() == 0)
+    {
+        resident.pop_front();
+    }
+
+    // Growing the list allocates, which may reclaim pages and take the lock
+    // again. So the list is grown with the lock released.
+    while resident.len() == resident.capacity() {
+        let wanted = max(2 * resident.capacity(), MIN_CAPACITY);
+        drop(resident);
+        let grown = VecDeque::with_capacity(wanted);
+        resident = RESIDENT.lock();
+        if grown.capacity() > resident.len() {
+            let old = mem::replace(&mut *resident, grown);
+            resident.extend(old);
+        }
+    }
+
+    resident.push_back((Arc::downgrade(pagetable), va));
+}
+
+/// Evicts clean pages of any process until `pages` frames are freed, or no
+/// more page can be evicted.
+///
+/// The caller must not hold the lock of any user page table.
+///
+/// ## Return
+/// The number of frames freed.
+pub fn reclaim(pages: usize) -> usize {
+    reclaim_imp(pages, None)
+}
+
+/// Like [`reclaim`], but leaves the pages of the current process alone, as the
+/// kernel may be accessing them through their frames.
+pub fn reclaim_others(pages: usize) -> usize {
+    reclaim_imp(pages, thread::current().pagetable.clone())
+}
+
+fn reclaim_imp(pages: usize, skip: Option<Arc<Mutex<PageTable>>>) -> usize {
+    let mut resident = RESIDENT.lock();
+    let mut freed = 0;
+
+    for _ in 0..resident.len() {
+        if freed >= pages {
+            break;
+        }
+
+        let (owner, va) = resident.pop_front().unwrap();
+        let pagetable = match owner.upgrade() {
+            Some(pagetable) => pagetable,
+            None => continue,
+        };
+        if skip
+            .as_ref()
+            .map_or(false, |skip| Arc::ptr_eq(skip, &pagetable))
+        {
+            resident.push_back((owner, va));
+            continue;
+        }
+
+        if evict(&mut pagetable.lock(), va) {
+            freed += 1;
+        }
+    }
+
+    freed
+}
+
+/// Takes away the frame of the page at `va`, if the page can be brought in
+/// again and no one else maps or pins the frame.
+fn evict(pagetable: &mut PageTable, va: usize) -> bool {
+    let entry = match pagetable.get_pte(va) {
+        Some(entry) if entry.is_valid() => *entry,
+        _ => return false,
+    };
+    let frame = entry.pa().into_va() as *mut u8;
+
+    if !entry.flag().contains(PTEFlags::SPT)
+        || entry.is_dirty()
+        || entry.is_cow()
+        || UserPool::page_refs(frame) > 1
+    {
+        return false;
+    }
+
+    pagetable.unmap(va);
+    unsafe { UserPool::dealloc_pages(frame, 1) };
+    true
+}
@@ -0,0 +1,276 @@
+//! File descriptor table.
+//!
+//! Descriptors 0, 1 and 2 are the standard streams. They refer to the console
+//! until [`dup2`] redirects them to a file, such as an end of a pipe.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::str;
+
+use crate::fs::disk::{Path, DISKFS};
+use crate::fs::{pipe, File, FileSys};
+use crate::io::{Read, Write};
+use crate::mem::userbuf::{copy_from_user, copy_to_user, strncpy_from_user};
+use crate::sbi::{console::stdout, console_putchar};
+use crate::sync::Mutex;
+use crate::thread;
+use crate::{OsError, Result};
+
+/// Descriptors below this are reserved for standard streams.
+const FD_BASE: isize = 3;
+
+const STDOUT: isize = 1;
+const STDERR: isize = 2;
+
+/// The most bytes moved by a single read or write
+const MAX_IO: usize = 64 << 10;
+
+/// Access modes and flags of [`open`]
+const O_ACCMODE: usize = 0x003;
+const O_RDONLY: usize = 0x000;
+const O_WRONLY: usize = 0x001;
+const O_RDWR: usize = 0x002;
+const O_CREATE: usize = 0x200;
+const O_TRUNC: usize = 0x400;
+
+/// Size of the longest path, terminator included
+pub(super) const PATH_MAX: usize = 128;
+
+/// Open files of a user process, indexed by file descriptor.
+///
+/// A [`File`] may be referred to by several descriptors, or by the tables
+/// of a process and its forked children, in which case they also share the
+/// file position.
+#[derive(Clone, Default)]
+pub struct FdTable(BTreeMap<isize, Arc<Mutex<File>>>);
+
+impl FdTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Installs `file` at the lowest free descriptor and returns it.
+    pub fn insert(&mut self, file: Arc<Mutex<File>>) -> isize {
+        let fd = (FD_BASE..)
+            .find(|fd| !self.0.contains_key(fd))
+            .expect("run out of file descriptors");
+        self.0.insert(fd, file);
+        fd
+    }
+
+    /// Installs `file` at `fd`, and returns the file it replaces.
+    pub fn insert_at(&mut self, fd: isize, file: Arc<Mutex<File>>) -> Option<Arc<Mutex<File>>> {
+        self.0.insert(fd, file)
+    }
+
+    pub fn get(&self, fd: isize) -> Option<Arc<Mutex<File>>> {
+        self.0.get(&fd).cloned()
+    }
+
+    pub fn remove(&mut self, fd: isize) -> Option<Arc<Mutex<File>>> {
+        self.0.remove(&fd)
+    }
+}
+
+/// Runs `f` on the descriptor table of the current process.
+fn with_fdt<T>(f: impl FnOnce(&mut FdTable) -> Result<T>) -> Result<T> {
+    let current = thread::current();
+    let userproc = current.userproc.as_ref().ok_or(OsError::UserError)?;
+    let mut fdt = userproc.fdt.lock();
+    f(&mut fdt)
+}
+
+/// A kernel buffer for moving up to `len` bytes, at most [`MAX_IO`].
+fn bounce(len: usize) -> Result<Vec<u8>> {
+    let len = min(len, MAX_IO);
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(len)
+        .map_err(|_| OsError::OutOfMemory)?;
+    buf.resize(len, 0);
+    Ok(buf)
+}
+
+/// Opens the file whose path is at `path` in user memory, and returns its
+/// descriptor.
+///
+/// The access mode in `flags` is one of `O_RDONLY`, `O_WRONLY` and `O_RDWR`.
+/// With `O_CREATE`, a missing file is created, and with `O_TRUNC`, the file is
+/// emptied.
+///
+/// ## Errors
+/// - [`OsError::BadPtr`]: `path` can't be read.
+/// - [`OsError::ArgumentTooLong`]: `path` doesn't fit in [`PATH_MAX`] bytes.
+/// - [`OsError::NoSuchFile`]: `path` is empty, or there is no such file and
+///   `O_CREATE` isn't set.
+/// - [`OsError::UserError`]: the access mode is invalid.
+pub fn open(path: *const u8, flags: usize) -> Result<isize> {
+    let mut buf = [0u8; PATH_MAX];
+    let path = read_path(&mut buf, path)?;
+
+    let (readable, writable) = match flags & O_ACCMODE {
+        O_RDONLY => (true, false),
+        O_WRONLY => (false, true),
+        O_RDWR => (true, true),
+        _ => return Err(OsError::UserError),
+    };
+
+    let mut file = if flags & O_CREATE != 0 && !Path::exists(path.into()) {
+        DISKFS.create(path.into())?
+    } else {
+        DISKFS.open(path.into())?
+    };
+    if flags & O_TRUNC != 0 {
+        file.set_len(0)?;
+    }
+    file.set_mode(readable, writable);
+
+    with_fdt(|fdt| Ok(fdt.insert(Arc::new(Mutex::new(file)))))
+}
+
+/// Copies the path at `path` in user memory to `buf`.
+///
+/// ## Errors
+/// - [`OsError::BadPtr`]: `path` can't be read.
+/// - [`OsError::ArgumentTooLong`]: `path` doesn't fit in [`PATH_MAX`] bytes.
+/// - [`OsError::NoSuchFile`]: `path` is empty.
+pub(super) fn read_path(buf: &mut [u8; PATH_MAX], path: *const u8) -> Result<&str> {
+    let len = strncpy_from_user(buf, path as usize)?;
+    if len == PATH_MAX {
+        return Err(OsError::ArgumentTooLong);
+    }
+    let path = str::from_utf8(&buf[..len]).map_err(|_| OsError::CstrFormatErr)?;
+    if path.is_empty() {
+        return Err(OsError::NoSuchFile);
+    }
+    Ok(path)
+}
+
+/// Creates a pipe, and stores the descriptors of its read end and its write
+/// end to `fds[0]` and `fds[1]` in user memory.
+///
+/// ## Errors
+/// [`OsError::BadPtr`] if `fds` can't be written. No descriptor is left
+/// open then.
+pub fn pipe(fds: *mut [i32; 2]) -> Result<()> {
+    let (reader, writer) = pipe::pipe();
+
+    with_fdt(|fdt| {
+        let read_fd = fdt.insert(Arc::new(Mutex::new(reader)));
+        let write_fd = fdt.insert(Arc::new(Mutex::new(writer)));
+
+        let mut bytes = [0u8; 8];
+        bytes[..4].copy_from_slice(&(read_fd as i32).to_ne_bytes());
+        bytes[4..].copy_from_slice(&(write_fd as i32).to_ne_bytes());
+        copy_to_user(fds as usize, &bytes).map_err(|e| {
+            fdt.remove(read_fd);
+            fdt.remove(write_fd);
+            e
+        })
+    })
+}
+
+/// Opens `fd` again at the lowest free descriptor.
+///
+/// ## Errors
+/// [`OsError::FileNotOpened`] if `fd` isn't an open file.
+pub fn dup(fd: isize) -> Result<isize> {
+    with_fdt(|fdt| {
+        let file = fdt.get(fd).ok_or(OsError::FileNotOpened)?;
+        Ok(fdt.insert(file))
+    })
+}
+
+/// Opens `fd` again as `new`, closing the file `new` referred to. Both then
+/// share the file position.
+///
+/// ## Errors
+/// - [`OsError::FileNotOpened`]: `fd` isn't an open file.
+/// - [`OsError::UserError`]: `new` is negative.
+pub fn dup2(fd: isize, new: isize) -> Result<isize> {
+    with_fdt(|fdt| {
+        let file = fdt.get(fd).ok_or(OsError::FileNotOpened)?;
+        if new < 0 {
+            return Err(OsError::UserError);
+        }
+        if fd != new {
+            fdt.insert_at(new, file);
+        }
+        Ok(new)
+    })
+}
+
+/// Closes `fd`. The file is released once no descriptor refers to it.
+///
+/// ## Errors
+/// [`OsError::FileNotOpened`] if `fd` isn't an open file.
+pub fn close(fd: isize) -> Result<()> {
+    with_fdt(|fdt| fdt.remove(fd).map(drop).ok_or(OsError::FileNotOpened))
+}
+
+/// Reads up to `len` bytes from `fd` into user memory at `buf`, and returns
+/// the number of bytes read. `0` means end of file.
+///
+/// A read from a pipe waits until some bytes are written, and returns those
+/// available. A single read moves at most [`MAX_IO`] bytes.
+///
+/// ## Errors
+/// - [`OsError::FileNotOpened`]: `fd` isn't an open file.
+/// - [`OsError::BadPtr`]: `buf` can't be written.
+pub fn read(fd: isize, buf: *mut u8, len: usize) -> Result<usize> {
+    // The descriptor table isn't locked while the read blocks.
+    let file = with_fdt(|fdt| fdt.get(fd).ok_or(OsError::FileNotOpened))?;
+
+    let mut bounce = bounce(len)?;
+    let read = file.lock().read(&mut bounce)?;
+    copy_to_user(buf as usize, &bounce[..read])?;
+
+    Ok(read)
+}
+
+/// Writes `len` bytes from user memory at `buf` to `fd`, and returns the
+/// number of bytes written. The standard output and error streams go to
+/// the console unless redirected.
+///
+/// ## Errors
+/// - [`OsError::FileNotOpened`]: `fd` isn't an open file.
+/// - [`OsError::BadPtr`]: `buf` can't be read.
+/// - [`OsError::BrokenPipe`]: `fd` is a pipe whose read end is closed.
+pub fn write(fd: isize, buf: *const u8, len: usize) -> Result<usize> {
+    let file = with_fdt(|fdt| match fdt.get(fd) {
+        Some(file) => Ok(Some(file)),
+        None if fd == STDOUT || fd == STDERR => Ok(None),
+        None => Err(OsError::FileNotOpened),
+    })?;
+
+    let mut bounce = bounce(len)?;
+    let mut written = 0;
+    while written < len {
+        let chunk = min(len - written, bounce.len());
+        copy_from_user(&mut bounce[..chunk], (buf as usize).wrapping_add(written))?;
+
+        let n = match &file {
+            Some(file) => match file.lock().write(&bounce[..chunk]) {
+                Ok(n) => n,
+                // Bytes already written are reported first.
+                Err(_) if written > 0 => break,
+                Err(e) => return Err(e),
+            },
+            None => {
+                let _stdout = stdout().lock();
+                bounce[..chunk]
+                    .iter()
+                    .for_each(|&byte| console_putchar(byte as usize));
+                chunk
+            }
+        };
+
+        written += n;
+        if n < chunk {
+            break;
+        }
+    }
+
+    Ok(written)
+}
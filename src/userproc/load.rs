@@ -1,33 +1,78 @@
+use alloc::string::String;
 use alloc::vec;
-use elf_rs::{Elf, ElfFile, ProgramHeaderEntry, ProgramHeaderFlags, ProgramType};
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::slice;
+use elf_rs::{
+    Elf, Elf64, ElfFile, ElfHeader64, ProgramHeader64, ProgramHeaderEntry, ProgramHeaderFlags,
+    ProgramType,
+};
 
 use crate::fs::File;
 use crate::io::prelude::*;
 use crate::mem::pagetable::{PTEFlags, PageTable};
-use crate::mem::palloc::UserPool;
+use crate::mem::palloc::{Palloc, UserPool};
 use crate::mem::{div_round_up, PageAlign, PhysAddr, PG_MASK, PG_SIZE};
+use crate::sbi::timer;
+use crate::thread::STACK_TOP;
+use crate::userproc::spt::{Backing, Page, SupplementalPageTable};
 use crate::{OsError, Result};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub(super) struct ExecInfo {
     pub entry_point: usize,
+    /// Where `argc` is stored, with `argv`, `envp` and the auxiliary vector
+    /// above it
     pub init_sp: usize,
+    /// The end of the highest loaded segment, where the heap starts
+    pub brk: usize,
+    pub segments: Vec<Segment>,
+    /// The user addresses of the `argv` and `envp` arrays
+    pub argv: usize,
+    pub envp: usize,
+    /// The user address of the program headers, and their number
+    phdr: usize,
+    phnum: usize,
+}
+
+/* Types of auxiliary vector entries */
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+const AT_RANDOM: usize = 25;
+
+/// Virtual addresses spanned by a loadable segment
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub start: usize,
+    pub end: usize,
+    pub flags: PTEFlags,
 }
 
 /// Loads an executable file
 ///
 /// ## Params
-/// - `pagetable`: User's pagetable. We install the mapping to executable codes into it.
+/// - `pagetable`: User's pagetable. We install the mapping to the user stack into it.
+/// - `spt`: User's supplemental page table. Segments are recorded here and loaded on demand.
+/// - `argv`, `envp`: Arguments and environment, passed on the user stack.
 ///
-/// ## Return
-/// On success, returns `Ok(usize, usize)`:
-/// - arg0: the entry point of user program
-/// - arg1: the initial sp of user program
-pub(super) fn load_executable(file: &mut File, pagetable: &mut PageTable) -> Result<ExecInfo> {
-    let exec_info = load_elf(file, pagetable)?;
+/// ## Errors
+/// [`OsError::ArgumentTooLong`] if `argv` and `envp` don't fit in the first
+/// page of the stack, see [`init_user_stack`].
+pub(super) fn load_executable(
+    file: &mut File,
+    pagetable: &mut PageTable,
+    spt: &mut SupplementalPageTable,
+    argv: &[String],
+    envp: &[String],
+) -> Result<ExecInfo> {
+    let mut exec_info = load_elf(file, spt)?;
 
     // Initialize user stack.
-    init_user_stack(pagetable, exec_info.init_sp);
+    init_user_stack(pagetable, &mut exec_info, argv, envp)?;
 
     // Forbid modifying executable file when running
     file.deny_write();
@@ -35,33 +80,88 @@ pub(super) fn load_executable(file: &mut File, pagetable: &mut PageTable) -> Res
     Ok(exec_info)
 }
 
-/// Parses the specified executable file and loads segments
-fn load_elf(file: &mut File, pagetable: &mut PageTable) -> Result<ExecInfo> {
+/// Parses the specified executable file and records its loadable segments.
+///
+/// Only the ELF header and the program headers are read here. Contents of
+/// segments are read from `file` page by page when they're first accessed.
+fn load_elf(file: &mut File, spt: &mut SupplementalPageTable) -> Result<ExecInfo> {
     // Ensure cursor is at the beginning
     file.rewind()?;
 
+    // Read the ELF header to locate program headers.
+    let mut buf = vec![0u8; size_of::<ElfHeader64>()];
+    file.read_exact(&mut buf)?;
+    let (phoff, phnum) = {
+        let elf = parse_elf(&buf)?;
+        let header = elf.elf_header();
+        (
+            header.program_header_offset() as usize,
+            header.program_header_entry_num() as usize,
+        )
+    };
+
+    // Then read everything up to the end of program headers.
     let len = file.len()?;
-    let mut buf = vec![0u8; len];
-    file.read(&mut buf)?;
+    let ph_top = phnum
+        .checked_mul(size_of::<ProgramHeader64>())
+        .and_then(|size| size.checked_add(phoff))
+        .filter(|&top| top <= len)
+        .ok_or(OsError::UnknownFormat)?;
+    buf.resize(ph_top.max(buf.len()), 0);
+    file.rewind()?;
+    file.read_exact(&mut buf)?;
 
-    let elf = match Elf::from_bytes(&buf) {
-        Ok(Elf::Elf64(elf)) => elf,
-        Ok(Elf::Elf32(_)) | Err(_) => return Err(OsError::UnknownFormat),
-    };
+    let elf = parse_elf(&buf)?;
+
+    // Program headers are in memory if a segment loads them.
+    let phdr = elf
+        .program_header_iter()
+        .find(|p| p.ph_type() == ProgramType::PHDR)
+        .map(|p| p.vaddr() as usize)
+        .or_else(|| {
+            elf.program_header_iter()
+                .filter(|p| p.ph_type() == ProgramType::LOAD)
+                .find(|p| (p.offset()..p.offset() + p.filesz()).contains(&(phoff as u64)))
+                .map(|p| p.vaddr() as usize + phoff - p.offset() as usize)
+        })
+        .unwrap_or(0);
 
-    // load each loadable segment into memory
-    elf.program_header_iter()
+    // record each loadable segment
+    let segments: Vec<_> = elf
+        .program_header_iter()
         .filter(|p| p.ph_type() == ProgramType::LOAD)
-        .for_each(|p| load_segment(&buf, &p, pagetable));
+        .map(|p| load_segment(file, &p, spt))
+        .collect::<Result<_>>()?;
 
     Ok(ExecInfo {
         entry_point: elf.elf_header().entry_point() as _,
-        init_sp: 0x80500000,
+        init_sp: STACK_TOP,
+        brk: segments.iter().map(|s| s.end).max().unwrap_or(0),
+        segments,
+        argv: 0,
+        envp: 0,
+        phdr,
+        phnum,
     })
 }
 
-/// Loads one segment and installs pagetable mappings
-fn load_segment(filebuf: &[u8], phdr: &ProgramHeaderEntry, pagetable: &mut PageTable) {
+fn parse_elf(buf: &[u8]) -> Result<Elf64<'_>> {
+    match Elf::from_bytes(buf) {
+        Ok(Elf::Elf64(elf)) => Ok(elf),
+        Ok(Elf::Elf32(_)) | Err(_) => Err(OsError::UnknownFormat),
+    }
+}
+
+/// Records the pages of one segment in the supplemental page table
+///
+/// ## Errors
+/// [`OsError::OutOfMemory`] if the segment has more pages than there are
+/// frames, so it could never be resident.
+fn load_segment(
+    file: &File,
+    phdr: &ProgramHeaderEntry,
+    spt: &mut SupplementalPageTable,
+) -> Result<Segment> {
     assert_eq!(phdr.ph_type(), ProgramType::LOAD);
 
     // Meaningful contents of this segment starts from `fileoff`.
@@ -83,46 +183,99 @@ fn load_segment(filebuf: &[u8], phdr: &ProgramHeaderEntry, pagetable: &mut PageT
     let pageoff = (phdr.vaddr() as usize) & PG_MASK;
     assert_eq!(fileoff & PG_MASK, pageoff);
 
-    // How many pages need to be allocated
+    // How many pages the segment spans
     let pages = div_round_up(pageoff + phdr.memsz() as usize, PG_SIZE);
+    if pages > Palloc::usage().total {
+        return Err(OsError::OutOfMemory);
+    }
     let mut readbytes = phdr.filesz() as usize + pageoff;
 
-    // Allocate & map pages
     for p in 0..pages {
-        let buf = unsafe { UserPool::alloc_pages(1) };
-        let page = unsafe { (buf as *mut [u8; PG_SIZE]).as_mut().unwrap() };
-
         // Read `readsz` bytes, fill remaining bytes with 0.
         let readsz = readbytes.min(PG_SIZE);
-        page[..readsz].copy_from_slice(&filebuf[readpos..readpos + readsz]);
-        page[readsz..].fill(0);
+        let backing = match readsz {
+            0 => Backing::Zero,
+            _ => Backing::File {
+                file: file.clone(),
+                offset: readpos,
+                read_bytes: readsz,
+            },
+        };
 
-        // The installed page will be freed when pagetable drops, which happens
-        // when user process exits. No manual resource collect is required.
+        // Frames are allocated on the first access to the page, or on the
+        // first write for zero pages, which map a shared frame until then.
+        // Once installed, they will be freed when pagetable drops, which
+        // happens when user process exits. No manual resource collect is
+        // required.
         let uaddr = ubase + p * PG_SIZE;
-        pagetable.map(buf.into(), uaddr, 1, leaf_flag);
+        spt.insert(uaddr, Page::new(backing, leaf_flag));
 
         readbytes -= readsz;
         readpos += readsz;
     }
 
     assert_eq!(readbytes, 0);
+
+    Ok(Segment {
+        start: phdr.vaddr() as usize,
+        end: (phdr.vaddr() + phdr.memsz()) as usize,
+        flags: leaf_flag,
+    })
 }
 
-/// Initializes the user stack.
-fn init_user_stack(pagetable: &mut PageTable, init_sp: usize) {
+/// Initializes the user stack, and lays out the arguments on it like the
+/// RISC-V System V ABI does. From `init_sp` up, there are:
+///
+/// - `argc`;
+/// - `argv`, which ends with a null pointer;
+/// - `envp`, which ends with a null pointer;
+/// - the auxiliary vector of type and value pairs, which ends with [`AT_NULL`];
+/// - the strings, and the 16 random bytes [`AT_RANDOM`] points at.
+///
+/// `init_sp` of `info` moves down to `argc`, and `argv` and `envp` of `info`
+/// are set.
+///
+/// ## Errors
+/// - [`OsError::ArgumentTooLong`]: everything above doesn't fit in the first
+///   page of the stack.
+/// - [`OsError::OutOfMemory`]: no frame or page table is left for the stack.
+fn init_user_stack(
+    pagetable: &mut PageTable,
+    info: &mut ExecInfo,
+    argv: &[String],
+    envp: &[String],
+) -> Result<()> {
+    let init_sp = info.init_sp;
     assert!(init_sp % PG_SIZE == 0, "initial sp address misaligns");
 
     // Allocate a page from UserPool as user stack.
-    let stack_va = unsafe { UserPool::alloc_pages(1) };
+    let stack_va = unsafe { UserPool::try_alloc_pages(1)? };
     let stack_pa = PhysAddr::from(stack_va);
 
     // Get the start address of stack page
     let stack_page_begin = PageAlign::floor(init_sp - 1);
 
+    // Fill in the page through the kernel, before it's mapped.
+    let page = unsafe {
+        stack_va.write_bytes(0, PG_SIZE);
+        slice::from_raw_parts_mut(stack_va, PG_SIZE)
+    };
+    let mut stack = Stack {
+        page,
+        base: stack_page_begin,
+        top: PG_SIZE,
+    };
+    if let Err(e) = stack.lay_out(info, argv, envp) {
+        unsafe { UserPool::dealloc_pages(stack_va, 1) };
+        return Err(e);
+    }
+
     // Install mapping
     let flags = PTEFlags::V | PTEFlags::R | PTEFlags::W | PTEFlags::U;
-    pagetable.map(stack_pa, stack_page_begin, PG_SIZE, flags);
+    if let Err(e) = pagetable.try_map(stack_pa, stack_page_begin, PG_SIZE, flags) {
+        unsafe { UserPool::dealloc_pages(stack_va, 1) };
+        return Err(e);
+    }
 
     #[cfg(feature = "debug")]
     kprintln!(
@@ -130,4 +283,99 @@ fn init_user_stack(pagetable: &mut PageTable, init_sp: usize) {
         stack_va,
         stack_page_begin
     );
+
+    Ok(())
+}
+
+/// The first page of a user stack, filled from its top down
+struct Stack<'a> {
+    page: &'a mut [u8],
+    /// The user address of the page
+    base: usize,
+    /// Offset of the lowest byte in use
+    top: usize,
+}
+
+impl Stack<'_> {
+    /// Pushes `bytes`, and returns their user address.
+    fn push(&mut self, bytes: &[u8]) -> Result<usize> {
+        self.top = self
+            .top
+            .checked_sub(bytes.len())
+            .ok_or(OsError::ArgumentTooLong)?;
+        self.page[self.top..self.top + bytes.len()].copy_from_slice(bytes);
+        Ok(self.base + self.top)
+    }
+
+    /// Pushes `s` with a NUL terminator, and returns its user address.
+    fn push_str(&mut self, s: &str) -> Result<usize> {
+        self.push(&[0])?;
+        self.push(s.as_bytes())
+    }
+
+    fn lay_out(&mut self, info: &mut ExecInfo, argv: &[String], envp: &[String]) -> Result<()> {
+        let random = self.push(&random_bytes())?;
+        let envs = envp
+            .iter()
+            .rev()
+            .map(|s| self.push_str(s))
+            .collect::<Result<Vec<_>>>()?;
+        let args = argv
+            .iter()
+            .rev()
+            .map(|s| self.push_str(s))
+            .collect::<Result<Vec<_>>>()?;
+
+        let auxv = [
+            (AT_PHDR, info.phdr),
+            (AT_PHENT, size_of::<ProgramHeader64>()),
+            (AT_PHNUM, info.phnum),
+            (AT_PAGESZ, PG_SIZE),
+            (AT_ENTRY, info.entry_point),
+            (AT_RANDOM, random),
+            (AT_NULL, 0),
+        ];
+
+        // Then the words, from `argc` at a 16-byte aligned stack pointer.
+        let mut words = Vec::with_capacity(3 + args.len() + envs.len() + 2 * auxv.len());
+        words.push(argv.len());
+        words.extend(args.iter().rev());
+        words.push(0);
+        words.extend(envs.iter().rev());
+        words.push(0);
+        auxv.iter()
+            .for_each(|&(ty, value)| words.extend([ty, value]));
+
+        let len = words.len() * size_of::<usize>();
+        self.top = self.top.checked_sub(len).ok_or(OsError::ArgumentTooLong)? & !0xf;
+        for (i, word) in words.iter().enumerate() {
+            let at = self.top + i * size_of::<usize>();
+            self.page[at..at + size_of::<usize>()].copy_from_slice(&word.to_ne_bytes());
+        }
+
+        let sp = self.base + self.top;
+        info.init_sp = sp;
+        info.argv = sp + size_of::<usize>();
+        info.envp = info.argv + (argv.len() + 1) * size_of::<usize>();
+        Ok(())
+    }
+}
+
+/// Bytes to seed user space generators with. They are hard to guess, but not
+/// fit for cryptography.
+fn random_bytes() -> [u8; 16] {
+    // SplitMix64, seeded with the cycle counter.
+    let mut state = timer::clock() as u64;
+    let mut next = || {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    };
+
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&next().to_ne_bytes());
+    bytes[8..].copy_from_slice(&next().to_ne_bytes());
+    bytes
 }
@@ -0,0 +1,182 @@
+//! Memory map of a user process.
+//!
+//! Lists what the address space of a process is made of: the segments of the
+//! executable, the heap, mapped files, shared memory and the stack, with how
+//! many pages of each are resident. It's printed on the console as a debugging
+//! aid, followed by the page table itself.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::mem::{PTEFlags, PageAlign, PageTable, PG_SIZE};
+use crate::thread::{Manager, Mutex, Thread, STACK_TOP};
+use crate::userproc::STACK_LIMIT;
+use crate::{OsError, Result};
+
+/// Access permissions in the form of `rwx`
+struct Perms(PTEFlags);
+
+impl fmt::Display for Perms {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let perm = |flag, c| if self.0.contains(flag) { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            perm(PTEFlags::R, 'r'),
+            perm(PTEFlags::W, 'w'),
+            perm(PTEFlags::X, 'x')
+        )
+    }
+}
+
+/// A range of user virtual addresses and what it holds
+struct Region {
+    start: usize,
+    end: usize,
+    flags: PTEFlags,
+    what: &'static str,
+    /// Id of a mapping, or key of a shared memory segment
+    id: Option<isize>,
+    /// Number of resident pages
+    resident: usize,
+}
+
+impl Region {
+    fn new(start: usize, end: usize, flags: PTEFlags, what: &'static str) -> Self {
+        Self {
+            start,
+            end,
+            flags,
+            what,
+            id: None,
+            resident: 0,
+        }
+    }
+}
+
+/// An optional id, printed as blanks if absent
+struct Id(Option<isize>);
+
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(id) => fmt::Display::fmt(&id, f),
+            None => f.pad(""),
+        }
+    }
+}
+
+/// A range of mappings in the page table: start, end, physical address and
+/// flags
+type Range = (usize, usize, usize, PTEFlags);
+
+/// Prints the memory map of the user process run by thread `tid`.
+///
+/// ## Errors
+/// [`OsError::UserError`] if there is no such thread, or it doesn't own a
+/// user process.
+pub fn memmap(tid: isize) -> Result<()> {
+    let thread = Manager::get().find(tid).ok_or(OsError::UserError)?;
+    match (thread.userproc.as_ref(), thread.pagetable.as_ref()) {
+        (Some(_), Some(pagetable)) => {
+            print(&thread, pagetable);
+            Ok(())
+        }
+        _ => Err(OsError::UserError),
+    }
+}
+
+fn print(thread: &Thread, pagetable: &Mutex<PageTable>) {
+    let userproc = thread.userproc.as_ref().unwrap();
+    let rw = PTEFlags::R | PTEFlags::W;
+    let mut regions = Vec::new();
+
+    for segment in userproc.segments.iter() {
+        regions.push(Region::new(
+            segment.start,
+            segment.end,
+            segment.flags,
+            "segment",
+        ));
+    }
+
+    let (start, end) = userproc.brk.lock().range();
+    if end > start {
+        regions.push(Region::new(start, end, rw, "heap"));
+    }
+
+    for (id, addr, len) in userproc.mmaps.lock().iter() {
+        regions.push(Region {
+            id: Some(id),
+            ..Region::new(addr, addr + len, rw, "mmap")
+        });
+    }
+
+    for (addr, key, len) in userproc.shm.lock().iter() {
+        regions.push(Region {
+            id: Some(key),
+            ..Region::new(addr, addr + len, rw, "shm")
+        });
+    }
+
+    // Nothing may be allocated while the page table is locked, as that could
+    // evict from it. Room for the stack and the ranges of the page table is
+    // made beforehand, and everything is printed once the lock is dropped.
+    regions.reserve(1);
+    let mut ranges: Vec<Range> = Vec::new();
+    loop {
+        let pagetable = pagetable.lock();
+        let mut count = 0;
+        pagetable.for_each_range(|_, _, _, _| count += 1);
+        if count > ranges.capacity() {
+            drop(pagetable);
+            ranges.reserve(count);
+            continue;
+        }
+
+        let resident = |va: usize| {
+            pagetable
+                .get_pte(va)
+                .map_or(false, |entry| entry.is_valid())
+        };
+
+        // The stack ends at its lowest resident page.
+        if let Some(bottom) = (STACK_TOP - STACK_LIMIT..STACK_TOP)
+            .step_by(PG_SIZE)
+            .find(|&va| resident(va))
+        {
+            regions.push(Region::new(bottom, STACK_TOP, rw, "stack"));
+        }
+
+        for region in regions.iter_mut() {
+            region.resident = (region.start.floor()..region.end)
+                .step_by(PG_SIZE)
+                .filter(|&va| resident(va))
+                .count();
+        }
+
+        pagetable.for_each_range(|start, end, pa, flags| ranges.push((start, end, pa, flags)));
+        break;
+    }
+
+    regions.sort_by_key(|region| region.start);
+
+    kprintln!("Memory map of {} ({}):", thread.name(), thread.id());
+    for region in regions {
+        kprintln!(
+            "  {:#010x}-{:#010x} {} {:<7} {:>3} {}/{} pages resident",
+            region.start,
+            region.end,
+            Perms(region.flags),
+            region.what,
+            Id(region.id),
+            region.resident,
+            (region.start.floor()..region.end).step_by(PG_SIZE).count()
+        );
+    }
+
+    kprintln!("Page table:");
+    for (start, end, pa, flags) in ranges {
+        kprintln!("  {:#x}-{:#x} -> {:#x} {:?}", start, end, pa, flags);
+    }
+}
@@ -0,0 +1,204 @@
+//! Memory-mapped files.
+//!
+//! A file is mapped into consecutive user pages. These pages are recorded in
+//! the supplemental page table and brought in on demand, just like segments of
+//! the executable. Pages modified through the mapping are written back to the
+//! file when the mapping is removed, either by [`munmap`] or at process exit.
+
+use alloc::collections::BTreeMap;
+use core::slice;
+
+use crate::fs::File;
+use crate::io::Seek;
+use crate::mem::palloc::UserPool;
+use crate::mem::{in_kernel_space, PTEFlags, PageAlign, PageTable, PG_SIZE};
+use crate::thread::{self, Mutex, STACK_TOP};
+use crate::userproc::spt::{Backing, Page, SupplementalPageTable};
+use crate::userproc::{UserProc, STACK_LIMIT};
+use crate::{OsError, Result};
+
+/// Identifies a mapping within a process.
+pub type MapId = isize;
+
+/// A file mapped at `addr`.
+struct Mapping {
+    file: File,
+    addr: usize,
+    len: usize,
+}
+
+impl Mapping {
+    /// Virtual addresses of the pages that the mapping spans.
+    fn pages(&self) -> impl Iterator<Item = usize> {
+        (self.addr..self.addr + self.len).step_by(PG_SIZE)
+    }
+
+    /// Removes every page of this mapping from the address space, and writes
+    /// back the pages modified through the mapping.
+    fn unmap(self, userproc: &UserProc, pagetable: &Mutex<PageTable>) {
+        let mut spt = userproc.spt.lock();
+
+        for va in self.pages() {
+            spt.remove(va);
+
+            let entry = match pagetable.lock().unmap(va) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let frame = entry.pa().into_va() as *mut u8;
+
+            if entry.is_dirty() {
+                let offset = va - self.addr;
+                let len = (self.len - offset).min(PG_SIZE);
+                let buf = unsafe { slice::from_raw_parts(frame, len) };
+
+                // Nobody can be told about a failed write-back when the process
+                // is exiting, so its failure is ignored in all cases.
+                let _ = self.file.write_at(buf, offset);
+            }
+
+            unsafe { UserPool::dealloc_pages(frame, 1) };
+        }
+    }
+}
+
+/// Files mapped into a user process.
+#[derive(Default)]
+pub struct MmapTable {
+    maps: BTreeMap<MapId, Mapping>,
+    next_id: MapId,
+}
+
+impl MmapTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn insert(&mut self, mapping: Mapping) -> MapId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.maps.insert(id, mapping);
+        id
+    }
+
+    fn remove(&mut self, id: MapId) -> Option<Mapping> {
+        self.maps.remove(&id)
+    }
+
+    /// The id, start address and length of every mapping
+    pub fn iter(&self) -> impl Iterator<Item = (MapId, usize, usize)> + '_ {
+        self.maps
+            .iter()
+            .map(|(&id, mapping)| (id, mapping.addr, mapping.len))
+    }
+}
+
+/// Maps the file opened as `fd` into the current process, starting at `addr`.
+///
+/// ## Errors
+/// - [`OsError::FileNotOpened`]: `fd` isn't an open file.
+/// - [`OsError::InvalidFileMode`]: `fd` isn't a regular file, but e.g. a pipe.
+/// - [`OsError::UserError`]: the file is empty.
+/// - [`OsError::BadPtr`]: `addr` is null or misaligned, or the mapping would
+///   overlap kernel space, the region reserved for the user stack, or any page
+///   that is already part of the process.
+pub fn mmap(fd: isize, addr: usize) -> Result<MapId> {
+    let current = thread::current();
+    let (userproc, pagetable) = match (current.userproc.as_ref(), current.pagetable.as_ref()) {
+        (Some(userproc), Some(pagetable)) => (userproc, pagetable),
+        _ => return Err(OsError::UserError),
+    };
+
+    let file = match userproc.fdt.lock().get(fd) {
+        Some(file) => file.lock().clone(),
+        None => return Err(OsError::FileNotOpened),
+    };
+    // Pages of a pipe can't be brought in again.
+    if !file.is_regular() {
+        return Err(OsError::InvalidFileMode);
+    }
+
+    let len = file.len()?;
+    if len == 0 {
+        return Err(OsError::UserError);
+    }
+
+    let mapping = Mapping { file, addr, len };
+
+    let mut spt = userproc.spt.lock();
+    check_region(&spt, pagetable, addr, len)?;
+
+    let flags = PTEFlags::V | PTEFlags::U | PTEFlags::R | PTEFlags::W;
+    for va in mapping.pages() {
+        let offset = va - addr;
+        let backing = Backing::File {
+            file: mapping.file.clone(),
+            offset,
+            read_bytes: (len - offset).min(PG_SIZE),
+        };
+        spt.insert(va, Page::new(backing, flags));
+    }
+
+    let id = userproc.mmaps.lock().insert(mapping);
+    Ok(id)
+}
+
+/// Checks that the `len` bytes at `addr` are free to be mapped by a process
+/// whose pages are recorded in `spt`.
+///
+/// ## Errors
+/// [`OsError::BadPtr`]: `addr` is null or misaligned, or the region would
+/// overlap kernel space, the region reserved for the user stack, or any page
+/// that is already part of the process.
+pub(super) fn check_region(
+    spt: &SupplementalPageTable,
+    pagetable: &Mutex<PageTable>,
+    addr: usize,
+    len: usize,
+) -> Result<()> {
+    let end = addr.checked_add(len.ceil()).ok_or(OsError::BadPtr)?;
+    if addr == 0 || !addr.is_aligned() || in_kernel_space(addr) || in_kernel_space(end - 1) {
+        return Err(OsError::BadPtr);
+    }
+
+    // The stack may grow into pages that are not mapped yet.
+    if addr < STACK_TOP && end > STACK_TOP - STACK_LIMIT {
+        return Err(OsError::BadPtr);
+    }
+
+    let overlaps = (addr..end).step_by(PG_SIZE).any(|va| {
+        spt.get(va).is_some()
+            || pagetable
+                .lock()
+                .get_pte(va)
+                .map_or(false, |entry| entry.is_valid())
+    });
+    match overlaps {
+        true => Err(OsError::BadPtr),
+        false => Ok(()),
+    }
+}
+
+/// Removes a mapping of the current process, writing back modified pages.
+///
+/// ## Errors
+/// [`OsError::UserError`] if `id` doesn't identify a mapping.
+pub fn munmap(id: MapId) -> Result<()> {
+    let current = thread::current();
+    let (userproc, pagetable) = match (current.userproc.as_ref(), current.pagetable.as_ref()) {
+        (Some(userproc), Some(pagetable)) => (userproc, pagetable),
+        _ => return Err(OsError::UserError),
+    };
+
+    let mapping = userproc.mmaps.lock().remove(id).ok_or(OsError::UserError)?;
+    mapping.unmap(userproc, pagetable);
+
+    Ok(())
+}
+
+/// Removes all mappings of `userproc`, writing back modified pages.
+pub(super) fn munmap_all(userproc: &UserProc, pagetable: &Mutex<PageTable>) {
+    let maps = core::mem::take(&mut userproc.mmaps.lock().maps);
+    maps.into_values()
+        .for_each(|mapping| mapping.unmap(userproc, pagetable));
+}
@@ -0,0 +1,217 @@
+//! Shared memory segments.
+//!
+//! A segment is a set of user frames named by a key. Processes that attach
+//! the same key map the same frames, so that their writes are visible to each
+//! other. The frames are reference counted by [`UserPool`]: the segment holds
+//! a reference to each of them, and so does every attachment. A forked child
+//! inherits the attachments of its parent.
+//!
+//! A segment lives as long as a process has it attached. Once the last process
+//! detaches it or exits, the segment is gone, and attaching its key creates a
+//! new one.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use crate::mem::palloc::UserPool;
+use crate::mem::{PTEFlags, PageAlign, PageTable, PhysAddr, PG_SIZE};
+use crate::sync::Lazy;
+use crate::thread::{self, Mutex};
+use crate::userproc::mmap::check_region;
+use crate::userproc::UserProc;
+use crate::{OsError, Result};
+
+/// Identifies a segment between processes.
+pub type ShmKey = isize;
+
+/// Segments that are attached by any process.
+static SEGMENTS: Lazy<Mutex<BTreeMap<ShmKey, Weak<Segment>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+struct Segment {
+    key: ShmKey,
+    /// Kernel virtual addresses of the frames, one per page
+    frames: Vec<usize>,
+}
+
+impl Segment {
+    /// Creates a segment of `pages` zeroed pages.
+    fn new(key: ShmKey, pages: usize) -> Result<Self> {
+        // Frames taken so far are freed on failure when the segment drops.
+        let mut segment = Self {
+            key,
+            frames: Vec::with_capacity(pages),
+        };
+        for _ in 0..pages {
+            let frame = unsafe { UserPool::try_alloc_pages(1)? };
+            unsafe { frame.write_bytes(0, PG_SIZE) };
+            segment.frames.push(frame as usize);
+        }
+
+        Ok(segment)
+    }
+
+    fn len(&self) -> usize {
+        self.frames.len() * PG_SIZE
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        let mut segments = SEGMENTS.lock();
+        // The key may have been taken by a new segment in the meantime.
+        if segments
+            .get(&self.key)
+            .map_or(false, |segment| segment.strong_count() == 0)
+        {
+            segments.remove(&self.key);
+        }
+        drop(segments);
+
+        for &frame in self.frames.iter() {
+            unsafe { UserPool::dealloc_pages(frame as *mut u8, 1) };
+        }
+    }
+}
+
+/// Segments attached to a user process, keyed by the address they start at.
+#[derive(Clone, Default)]
+pub struct ShmTable(BTreeMap<usize, Arc<Segment>>);
+
+impl ShmTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The start address, key and length of every attached segment
+    pub fn iter(&self) -> impl Iterator<Item = (usize, ShmKey, usize)> + '_ {
+        self.0
+            .iter()
+            .map(|(&addr, segment)| (addr, segment.key, segment.len()))
+    }
+
+    /// Whether `va` lies in an attached segment.
+    pub fn contains(&self, va: usize) -> bool {
+        self.0
+            .range(..=va)
+            .next_back()
+            .map_or(false, |(&addr, segment)| va < addr + segment.len())
+    }
+}
+
+/// Attaches the segment of `key` to the current process at `addr`. If no
+/// process has the segment attached, a new one of `size` bytes is created.
+///
+/// ## Errors
+/// - [`OsError::UserError`]: `size` is zero, or the existing segment is
+///   smaller than `size`.
+/// - [`OsError::BadPtr`]: the segment can't be mapped at `addr`, see
+///   [`mmap`](super::mmap).
+/// - [`OsError::OutOfMemory`]: no frames or page tables are left for the
+///   segment.
+pub fn shmat(key: ShmKey, size: usize, addr: usize) -> Result<usize> {
+    let current = thread::current();
+    let (userproc, pagetable) = match (current.userproc.as_ref(), current.pagetable.as_ref()) {
+        (Some(userproc), Some(pagetable)) => (userproc, pagetable),
+        _ => return Err(OsError::UserError),
+    };
+
+    if size == 0 {
+        return Err(OsError::UserError);
+    }
+
+    let segment = get_or_create(key, size)?;
+
+    let spt = userproc.spt.lock();
+    check_region(&spt, pagetable, addr, segment.len())?;
+
+    let flags = PTEFlags::V | PTEFlags::U | PTEFlags::R | PTEFlags::W;
+    let mut table = pagetable.lock();
+    for (i, &frame) in segment.frames.iter().enumerate() {
+        let mapped = table.try_map(
+            PhysAddr::from(frame as *const u8),
+            addr + i * PG_SIZE,
+            PG_SIZE,
+            flags,
+        );
+        if let Err(e) = mapped {
+            drop(table);
+            detach(&segment, addr, pagetable);
+            return Err(e);
+        }
+        unsafe { UserPool::share_page(frame as *mut u8) };
+    }
+    drop(table);
+    drop(spt);
+
+    userproc.shm.lock().0.insert(addr, segment);
+    Ok(addr)
+}
+
+/// Finds the segment of `key`, or creates one of `size` bytes.
+fn get_or_create(key: ShmKey, size: usize) -> Result<Arc<Segment>> {
+    let existing = SEGMENTS.lock().get(&key).and_then(Weak::upgrade);
+    let segment = match existing {
+        Some(segment) => segment,
+        None => {
+            // Allocating may evict pages, so no lock is held meanwhile.
+            let created = Arc::new(Segment::new(key, size.ceil() / PG_SIZE)?);
+
+            let mut segments = SEGMENTS.lock();
+            let raced = segments.get(&key).and_then(Weak::upgrade);
+            if raced.is_none() {
+                segments.insert(key, Arc::downgrade(&created));
+            }
+            drop(segments);
+
+            // Another process may have created the segment in the meantime.
+            raced.unwrap_or(created)
+        }
+    };
+
+    match segment.len() < size {
+        true => Err(OsError::UserError),
+        false => Ok(segment),
+    }
+}
+
+/// Detaches the segment attached at `addr` from the current process.
+///
+/// ## Errors
+/// [`OsError::UserError`] if no segment is attached at `addr`.
+pub fn shmdt(addr: usize) -> Result<()> {
+    let current = thread::current();
+    let (userproc, pagetable) = match (current.userproc.as_ref(), current.pagetable.as_ref()) {
+        (Some(userproc), Some(pagetable)) => (userproc, pagetable),
+        _ => return Err(OsError::UserError),
+    };
+
+    let segment = userproc
+        .shm
+        .lock()
+        .0
+        .remove(&addr)
+        .ok_or(OsError::UserError)?;
+    detach(&segment, addr, pagetable);
+
+    Ok(())
+}
+
+/// Detaches all segments of `userproc`.
+pub(super) fn shmdt_all(userproc: &UserProc, pagetable: &Mutex<PageTable>) {
+    let segments = core::mem::take(&mut userproc.shm.lock().0);
+    segments
+        .iter()
+        .for_each(|(&addr, segment)| detach(segment, addr, pagetable));
+}
+
+/// Drops the pages of `segment` mapped at `addr`.
+fn detach(segment: &Segment, addr: usize, pagetable: &Mutex<PageTable>) {
+    let mut pagetable = pagetable.lock();
+    for va in (addr..addr + segment.len()).step_by(PG_SIZE) {
+        if let Some(entry) = pagetable.unmap(va) {
+            unsafe { UserPool::dealloc_pages(entry.pa().into_va() as *mut u8, 1) };
+        }
+    }
+}
@@ -0,0 +1,307 @@
+//! Signals.
+//!
+//! A signal sent to a process stays pending until the process next returns
+//! to user mode, where [`deliver`] takes the action registered for it. The
+//! default action terminates the process, except for [`SIGCHLD`], which is
+//! ignored. A user handler runs on the user stack, below a [`SigFrame`] that
+//! saves the interrupted registers. It returns into a restorer, given with
+//! [`sigaction`], which calls [`sigreturn`] to resume the interrupted code.
+//! Signals are sent to a process, and taken by whichever of its threads
+//! returns to user mode first.
+//!
+//! The signal being handled is blocked until its handler returns. Faults
+//! [`force`] their signal, so a fault in a handler of that very signal
+//! terminates the process rather than looping.
+
+use core::mem::{size_of, MaybeUninit};
+use core::slice;
+
+use crate::mem::userbuf::{copy_from_user, copy_to_user};
+use crate::thread::{self, Manager};
+use crate::trap::Frame;
+use crate::userproc::{self, UserProc};
+use crate::{OsError, Result};
+
+/// Number of signals. Valid signals are `1..NSIG`.
+pub const NSIG: usize = 32;
+
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGBUS: usize = 7;
+/// Terminates the process. It can't be handled, ignored or blocked.
+pub const SIGKILL: usize = 9;
+pub const SIGSEGV: usize = 11;
+pub const SIGCHLD: usize = 17;
+
+/// Handler values of [`sigaction`] for the default action, and for ignoring
+/// the signal.
+const SIG_DFL: usize = 0;
+const SIG_IGN: usize = 1;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Action {
+    Default,
+    Ignore,
+    /// A user function, and the restorer it returns into
+    Handle {
+        handler: usize,
+        restorer: usize,
+    },
+}
+
+/// Signal states of a process
+#[derive(Clone)]
+pub struct Signals {
+    /// Bit `n` is set if signal `n` is pending.
+    pending: u32,
+    /// Bit `n` is set if signal `n` is blocked.
+    blocked: u32,
+    actions: [Action; NSIG],
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Self {
+            pending: 0,
+            blocked: 0,
+            actions: [Action::Default; NSIG],
+        }
+    }
+}
+
+impl Signals {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A forked child keeps the actions and blocked signals, but none of the
+    /// pending ones.
+    pub fn fork(&self) -> Self {
+        Self {
+            pending: 0,
+            ..self.clone()
+        }
+    }
+
+    /// Takes the lowest pending signal that isn't blocked.
+    fn take(&mut self) -> Option<(usize, Action)> {
+        let deliverable = self.pending & !self.blocked;
+        if deliverable == 0 {
+            return None;
+        }
+
+        let sig = deliverable.trailing_zeros() as usize;
+        self.pending &= !bit(sig);
+        Some((sig, self.actions[sig]))
+    }
+}
+
+/// The registers saved on the user stack while a handler runs
+#[repr(C)]
+struct SigFrame {
+    x: [usize; 32],
+    sepc: usize,
+    /// Signals blocked before the handler ran
+    blocked: usize,
+}
+
+fn bit(sig: usize) -> u32 {
+    1 << sig
+}
+
+fn check(sig: usize) -> Result<()> {
+    match sig {
+        0 => Err(OsError::UserError),
+        sig if sig >= NSIG => Err(OsError::UserError),
+        _ => Ok(()),
+    }
+}
+
+/// Sends `sig` to the process `pid`. `0` names the calling process, as there
+/// are no process groups. Signal `0` only checks that `pid` exists.
+///
+/// A process blocked in the kernel sees the signal once it returns to user
+/// mode.
+///
+/// ## Errors
+/// [`OsError::UserError`]: `sig` isn't a signal, or `pid` isn't a user process.
+pub fn kill(pid: isize, sig: usize) -> Result<()> {
+    if sig != 0 {
+        check(sig)?;
+    }
+
+    let target = match pid {
+        0 => thread::current(),
+        pid => Manager::get().find(pid).ok_or(OsError::UserError)?,
+    };
+    let userproc = target.userproc.as_ref().ok_or(OsError::UserError)?;
+
+    if sig != 0 {
+        userproc.signals.lock().pending |= bit(sig);
+    }
+    Ok(())
+}
+
+/// Registers `handler` for `sig` in the current process. A user function
+/// returns into `restorer`, which must call [`sigreturn`].
+///
+/// ## Return
+/// The previous handler. [`SIG_DFL`] and [`SIG_IGN`] stand for the default
+/// action and for ignoring the signal.
+///
+/// ## Errors
+/// [`OsError::UserError`]: `sig` isn't a signal, or it's [`SIGKILL`].
+pub fn sigaction(sig: usize, handler: usize, restorer: usize) -> Result<usize> {
+    check(sig)?;
+    if sig == SIGKILL {
+        return Err(OsError::UserError);
+    }
+
+    let current = thread::current();
+    let userproc = current.userproc.as_ref().ok_or(OsError::UserError)?;
+
+    let action = match handler {
+        SIG_DFL => Action::Default,
+        SIG_IGN => Action::Ignore,
+        handler => Action::Handle { handler, restorer },
+    };
+
+    let mut signals = userproc.signals.lock();
+    let old = match core::mem::replace(&mut signals.actions[sig], action) {
+        Action::Default => SIG_DFL,
+        Action::Ignore => SIG_IGN,
+        Action::Handle { handler, .. } => handler,
+    };
+    // A signal ignored now is dropped rather than left pending.
+    if action == Action::Ignore {
+        signals.pending &= !bit(sig);
+    }
+
+    Ok(old)
+}
+
+/// Raises `sig` on the current process for a fault it caused. If the process
+/// ignores or blocks `sig`, it gets the default action instead, as resuming
+/// the faulting instruction would fault again.
+pub fn force(sig: usize) {
+    let current = thread::current();
+    let userproc = current
+        .userproc
+        .as_ref()
+        .expect("only user processes receive signals");
+
+    let mut signals = userproc.signals.lock();
+    if signals.actions[sig] == Action::Ignore || signals.blocked & bit(sig) != 0 {
+        signals.actions[sig] = Action::Default;
+        signals.blocked &= !bit(sig);
+    }
+    signals.pending |= bit(sig);
+}
+
+/// Takes the action for a pending signal of the current process, which is
+/// about to return to user mode with `frame`.
+///
+/// This function doesn't return if the process terminates.
+pub fn deliver(frame: &mut Frame) {
+    let current = thread::current();
+    let userproc = match current.userproc.as_ref() {
+        Some(userproc) => userproc,
+        None => return,
+    };
+
+    // The process exits, so this thread goes away instead.
+    if userproc.threads.exiting() {
+        drop(current);
+        userproc::thread_exit(-1);
+    }
+
+    let mut signals = userproc.signals.lock();
+    let (sig, action) = loop {
+        match signals.take() {
+            Some((_, Action::Ignore)) => continue,
+            Some((SIGCHLD, Action::Default)) => continue,
+            Some(pending) => break pending,
+            None => return,
+        }
+    };
+
+    let (handler, restorer) = match action {
+        Action::Handle { handler, restorer } if sig != SIGKILL => (handler, restorer),
+        _ => {
+            drop(signals);
+            kprintln!("User thread {} killed by signal {}.", current.name(), sig);
+            drop(current);
+            userproc::exit(-1);
+        }
+    };
+
+    let sigframe = SigFrame {
+        x: frame.x,
+        sepc: frame.sepc,
+        blocked: signals.blocked as usize,
+    };
+    let sp = frame.x[2].wrapping_sub(size_of::<SigFrame>()) & !0xf;
+
+    if push(userproc, sp, &sigframe).is_err() {
+        drop(signals);
+        kprintln!(
+            "User thread {} killed: no stack for the handler of signal {}.",
+            current.name(),
+            sig
+        );
+        drop(current);
+        userproc::exit(-1);
+    }
+
+    signals.blocked |= bit(sig);
+
+    frame.x[1] = restorer;
+    frame.x[2] = sp;
+    frame.x[10] = sig;
+    frame.sepc = handler;
+}
+
+/// Writes `sigframe` to the user stack at `sp`.
+fn push(userproc: &UserProc, sp: usize, sigframe: &SigFrame) -> Result<()> {
+    // The frame lies below the stack pointer, which bounds the stack growth.
+    userproc.set_sp(sp);
+
+    let bytes =
+        unsafe { slice::from_raw_parts(sigframe as *const _ as *const u8, size_of::<SigFrame>()) };
+    copy_to_user(sp, bytes)
+}
+
+/// Returns from a signal handler, restoring the registers and the blocked
+/// signals saved when it was called. The stack pointer in `frame` must point
+/// at the [`SigFrame`], as it did when the handler started.
+///
+/// ## Return
+/// The restored `a0`, which the syscall returns in its place.
+///
+/// This function doesn't return if the frame can't be read, and the process
+/// terminates.
+pub fn sigreturn(frame: &mut Frame) -> isize {
+    let current = thread::current();
+    let userproc = match current.userproc.as_ref() {
+        Some(userproc) => userproc,
+        None => return -1,
+    };
+
+    let mut sigframe = MaybeUninit::<SigFrame>::uninit();
+    let bytes = unsafe {
+        slice::from_raw_parts_mut(sigframe.as_mut_ptr() as *mut u8, size_of::<SigFrame>())
+    };
+    if copy_from_user(bytes, frame.x[2]).is_err() {
+        kprintln!("User thread {} killed: bad signal frame.", current.name());
+        drop(current);
+        userproc::exit(-1);
+    }
+    let sigframe = unsafe { sigframe.assume_init() };
+
+    // Only registers are restored. `sstatus` stays under kernel control.
+    frame.x[1..].copy_from_slice(&sigframe.x[1..]);
+    frame.sepc = sigframe.sepc;
+    userproc.signals.lock().blocked = sigframe.blocked as u32 & !bit(SIGKILL);
+
+    frame.x[10] as isize
+}
@@ -0,0 +1,183 @@
+//! Supplemental page table.
+//!
+//! A user page doesn't have to be resident when the process starts. The
+//! supplemental page table records, for every page of a user address space,
+//! where its contents come from. On the first access, the page fault handler
+//! consults it to allocate a frame, fill it and install the mapping.
+
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+use crate::fs::File;
+use crate::mem::palloc::UserPool;
+use crate::mem::{PTEFlags, PageAlign, PageTable, PhysAddr, PG_SIZE};
+use crate::thread::Mutex;
+use crate::{OsError, Result};
+
+/// Where the contents of a page come from.
+#[derive(Clone)]
+pub enum Backing {
+    /// The first `read_bytes` bytes are read from `file` at `offset`,
+    /// the remaining bytes of the page are zeroed.
+    File {
+        file: File,
+        offset: usize,
+        read_bytes: usize,
+    },
+    /// A page filled with zeros, e.g. BSS.
+    Zero,
+}
+
+/// Describes a single user page that can be brought into memory.
+#[derive(Clone)]
+pub struct Page {
+    backing: Backing,
+    /// Flags to install when the page becomes resident.
+    flags: PTEFlags,
+}
+
+impl Page {
+    pub fn new(backing: Backing, flags: PTEFlags) -> Self {
+        Self { backing, flags }
+    }
+
+    pub fn flags(&self) -> PTEFlags {
+        self.flags
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags.contains(PTEFlags::W)
+    }
+
+    /// Fills a newly allocated frame with the contents of this page.
+    fn fill(&self, frame: &mut [u8; PG_SIZE]) -> Result<()> {
+        match &self.backing {
+            Backing::File {
+                file,
+                offset,
+                read_bytes,
+            } => {
+                let read_bytes = *read_bytes;
+                if file.read_at(&mut frame[..read_bytes], *offset)? != read_bytes {
+                    return Err(OsError::UnexpectedEOF);
+                }
+                frame[read_bytes..].fill(0);
+            }
+            Backing::Zero => frame.fill(0),
+        }
+
+        Ok(())
+    }
+}
+
+/// The frame of zeros that untouched zero pages of all processes map.
+///
+/// It's never written. The frame keeps an owner of its own besides the page
+/// tables that map it, so it's never freed, and a write to it always makes a
+/// private copy, see [`PageTable::copy_on_write`].
+///
+/// ## Errors
+/// [`OsError::OutOfMemory`] if the frame isn't allocated yet, and no frame is
+/// left for it.
+fn zero_frame() -> Result<*mut u8> {
+    static ZERO: AtomicUsize = AtomicUsize::new(0);
+
+    let frame = ZERO.load(SeqCst);
+    if frame != 0 {
+        return Ok(frame as *mut u8);
+    }
+
+    let frame = unsafe { UserPool::try_alloc_pages(1)? };
+    unsafe { frame.write_bytes(0, PG_SIZE) };
+
+    // Another fault may have allocated it meanwhile.
+    match ZERO.compare_exchange(0, frame as usize, SeqCst, SeqCst) {
+        Ok(_) => Ok(frame),
+        Err(other) => {
+            unsafe { UserPool::dealloc_pages(frame, 1) };
+            Ok(other as *mut u8)
+        }
+    }
+}
+
+/// Per-process table of pages that are loaded on demand.
+#[derive(Clone, Default)]
+pub struct SupplementalPageTable {
+    /// Keyed by the page-aligned user virtual address.
+    pages: BTreeMap<usize, Page>,
+}
+
+impl SupplementalPageTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records how to bring in the page at `va`, replacing any previous record.
+    pub fn insert(&mut self, va: usize, page: Page) {
+        assert!(va.is_aligned(), "address misaligns");
+        self.pages.insert(va, page);
+    }
+
+    /// Forgets the page at `va`.
+    pub fn remove(&mut self, va: usize) -> Option<Page> {
+        self.pages.remove(&va)
+    }
+
+    /// Finds the page that contains `va`.
+    pub fn get(&self, va: usize) -> Option<&Page> {
+        self.pages.get(&va.floor())
+    }
+
+    /// Allocates a frame for the page that contains `va`, fills it and
+    /// installs the mapping into `pagetable`.
+    ///
+    /// A zero page that is only read maps the shared zero frame instead, see
+    /// [`zero_frame`]. Writable ones are mapped copy-on-write, so the first
+    /// write gives them a private frame.
+    ///
+    /// ## Errors
+    /// - [`OsError::BadPtr`]: `va` doesn't belong to any recorded page, or the
+    ///   access is a write to a read-only page.
+    /// - [`OsError::OutOfMemory`]: no frame or page table is left for the page.
+    pub fn load(&self, va: usize, write: bool, pagetable: &Mutex<PageTable>) -> Result<()> {
+        let page = self.get(va).ok_or(OsError::BadPtr)?;
+        if write && !page.is_writable() {
+            return Err(OsError::BadPtr);
+        }
+
+        let (frame, flags) = match page.backing {
+            Backing::Zero if !write => {
+                let frame = zero_frame()?;
+                unsafe { UserPool::share_page(frame) };
+
+                let mut flags = page.flags - PTEFlags::W;
+                flags.set(PTEFlags::COW, page.is_writable());
+                (frame, flags)
+            }
+            _ => {
+                let frame = unsafe { UserPool::try_alloc_pages(1)? };
+
+                // Filling the frame may sleep on disk I/O, so do it before
+                // taking the page table lock.
+                let contents = unsafe { (frame as *mut [u8; PG_SIZE]).as_mut().unwrap() };
+                if let Err(e) = page.fill(contents) {
+                    unsafe { UserPool::dealloc_pages(frame, 1) };
+                    return Err(e);
+                }
+                (frame, page.flags)
+            }
+        };
+
+        // The page may be evicted as long as it stays clean.
+        let va = va.floor();
+        let mut pagetable = pagetable.lock();
+        let mapped = pagetable.try_map(PhysAddr::from(frame), va, PG_SIZE, flags | PTEFlags::SPT);
+        if let Err(e) = mapped {
+            unsafe { UserPool::dealloc_pages(frame, 1) };
+            return Err(e);
+        }
+        pagetable.flush_tlb(va);
+
+        Ok(())
+    }
+}
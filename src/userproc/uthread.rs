@@ -0,0 +1,166 @@
+//! Threads of a user process.
+//!
+//! All threads of a process share its [`UserProc`] and its page table. Each
+//! of them runs on a kernel thread of its own, whose id is the thread id. A
+//! thread runs on a user stack given by its creator, and leaves with
+//! [`thread_exit`], or when the process exits. The exit value is kept until
+//! another thread of the process picks it up with [`thread_join`].
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::mem::MaybeUninit;
+
+use crate::sync::{Condvar, Mutex};
+use crate::thread::{self, Manager};
+use crate::trap::Frame;
+use crate::userproc::{self, UserProc};
+use crate::{OsError, Result};
+
+struct State {
+    /// Threads that haven't left yet
+    alive: usize,
+    /// Exit values of threads not joined yet, by thread id
+    exited: BTreeMap<isize, isize>,
+    /// Set once the process exits. Remaining threads leave on their way back
+    /// to user mode.
+    exiting: bool,
+}
+
+/// Threads of a user process
+pub struct ThreadGroup {
+    state: Mutex<State>,
+    /// Signaled when a thread leaves
+    exited: Condvar,
+}
+
+impl ThreadGroup {
+    /// A group with the initial thread of a process.
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                alive: 1,
+                exited: BTreeMap::new(),
+                exiting: false,
+            }),
+            exited: Condvar::new(),
+        }
+    }
+
+    pub fn exiting(&self) -> bool {
+        self.state.lock().exiting
+    }
+
+    /// Marks the process as exiting.
+    ///
+    /// ## Return
+    /// `false` if it was exiting already.
+    pub(super) fn kill(&self) -> bool {
+        let killed = !core::mem::replace(&mut self.state.lock().exiting, true);
+        // Joiners have to leave as well.
+        self.exited.notify_all();
+        killed
+    }
+
+    /// Records that thread `tid` leaves with `value`.
+    ///
+    /// ## Return
+    /// `true` if it's the last thread, and the process wasn't exiting yet.
+    /// The caller must tear down the process then.
+    pub(super) fn leave(&self, tid: isize, value: isize) -> bool {
+        let mut state = self.state.lock();
+        state.alive -= 1;
+        state.exited.insert(tid, value);
+        self.exited.notify_all();
+
+        let last = state.alive == 0 && !state.exiting;
+        if last {
+            state.exiting = true;
+        }
+        last
+    }
+}
+
+/// Starts a thread in the current process, which calls `entry` with `arg` on
+/// the user stack whose top is `stack`. Returning from `entry` faults, so it
+/// must end with [`thread_exit`].
+///
+/// ## Return
+/// The id of the new thread.
+///
+/// ## Errors
+/// - [`OsError::UserError`]: the current thread isn't in a user process, or
+///   the process is exiting.
+/// - [`OsError::OutOfMemory`]: no kernel stack is left for the thread.
+pub fn thread_create(entry: usize, arg: usize, stack: usize) -> Result<isize> {
+    let current = thread::current();
+    let userproc = current.userproc.as_ref().ok_or(OsError::UserError)?;
+
+    {
+        let mut state = userproc.threads.state.lock();
+        if state.exiting {
+            return Err(OsError::UserError);
+        }
+        state.alive += 1;
+    }
+
+    let mut frame = unsafe { MaybeUninit::<Frame>::zeroed().assume_init() };
+    frame.sepc = entry;
+    frame.x[2] = stack & !0xf;
+    frame.x[10] = arg;
+
+    thread::Builder::new(move || userproc::start(frame))
+        .name(current.name())
+        .sibling_of(&current)
+        .try_spawn()
+        .map(|thread| thread.id())
+        .map_err(|e| {
+            userproc.threads.state.lock().alive -= 1;
+            e
+        })
+}
+
+/// Ends the current thread with `value`. The process exits with `value` if
+/// it's the last thread.
+///
+/// Panic if the current thread isn't in a user process.
+pub fn thread_exit(value: isize) -> ! {
+    userproc::leave(value, false)
+}
+
+/// Waits for thread `tid` of the current process to leave.
+///
+/// ## Return
+/// The exit value of the thread.
+///
+/// ## Errors
+/// [`OsError::UserError`]: `tid` is the current thread, or it isn't a thread of
+/// the current process, or it has been joined already, or the process exits
+/// meanwhile.
+pub fn thread_join(tid: isize) -> Result<isize> {
+    let current = thread::current();
+    let userproc = current.userproc.as_ref().ok_or(OsError::UserError)?;
+    if tid == current.id() {
+        return Err(OsError::UserError);
+    }
+
+    let group = &userproc.threads;
+    let mut state = group.state.lock();
+    loop {
+        if let Some(value) = state.exited.remove(&tid) {
+            return Ok(value);
+        }
+        // The caller leaves on its way back to user mode.
+        if state.exiting || !is_sibling(userproc, tid) {
+            return Err(OsError::UserError);
+        }
+        group.exited.wait(&mut state);
+    }
+}
+
+/// Whether thread `tid` is alive in `userproc`.
+fn is_sibling(userproc: &Arc<UserProc>, tid: isize) -> bool {
+    Manager::get()
+        .find(tid)
+        .and_then(|thread| thread.userproc.clone())
+        .map_or(false, |other| Arc::ptr_eq(&other, userproc))
+}
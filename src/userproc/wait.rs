@@ -0,0 +1,89 @@
+//! Exit values of user processes.
+//!
+//! The thread that creates a process, with [`execute`](super::execute) or
+//! [`fork`](super::fork), is its parent. The process keeps its exit value in
+//! an [`Exit`] shared with its parent, which picks the value up once with
+//! [`wait`]. Children of a parent that is gone are forgotten.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+use crate::sync::{Condvar, Lazy, Mutex};
+use crate::thread;
+
+/// The exit value of a process, set once it exits.
+pub struct Exit {
+    value: Mutex<Option<isize>>,
+    /// Signaled when the value is set
+    exited: Condvar,
+}
+
+impl Exit {
+    pub fn new() -> Self {
+        Self {
+            value: Mutex::new(None),
+            exited: Condvar::new(),
+        }
+    }
+
+    /// Sets the exit value, unless it's set already.
+    pub(super) fn set(&self, value: isize) {
+        let mut exit = self.value.lock();
+        if exit.is_none() {
+            *exit = Some(value);
+        }
+        self.exited.notify_all();
+    }
+
+    /// Blocks until the exit value is set.
+    fn get(&self) -> isize {
+        let mut exit = self.value.lock();
+        loop {
+            if let Some(value) = *exit {
+                return value;
+            }
+            self.exited.wait(&mut exit);
+        }
+    }
+}
+
+struct Child {
+    /// Tid of the thread that created the process
+    parent: isize,
+    exit: Arc<Exit>,
+}
+
+/// Processes not waited for yet, by the tid of their initial thread.
+static CHILDREN: Lazy<Mutex<BTreeMap<isize, Child>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Records process `tid`, which exits through `exit`, as a child of the
+/// current thread.
+pub(super) fn adopt(tid: isize, exit: Arc<Exit>) {
+    let parent = thread::current().id();
+    CHILDREN.lock().insert(tid, Child { parent, exit });
+}
+
+/// Forgets the children of thread `parent`, which leaves.
+pub(super) fn orphan(parent: isize) {
+    CHILDREN.lock().retain(|_, child| child.parent != parent);
+}
+
+/// Waits for a child thread, which must own a user process.
+///
+/// ## Return
+/// - `Some(exit_value)`
+/// - `None`: if tid was not created by the current thread, or it has been
+///   waited for already.
+pub fn wait(tid: isize) -> Option<isize> {
+    let parent = thread::current().id();
+
+    let exit = {
+        let mut children = CHILDREN.lock();
+        match children.get(&tid) {
+            Some(child) if child.parent == parent => children.remove(&tid)?.exit,
+            _ => return None,
+        }
+    };
+
+    Some(exit.get())
+}
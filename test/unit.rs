@@ -1,7 +1,12 @@
 mod fs;
 mod malloc;
+mod pagetable;
+#[cfg(feature = "mem-poison")]
+mod poison;
+mod slab;
 mod sync;
 mod thread;
+mod userbuf;
 mod virtio;
 
 pub fn main() {
@@ -35,6 +40,28 @@ pub fn main() {
     #[cfg(feature = "test-mem-malloc")]
     malloc::main();
 
+    #[cfg(feature = "test-mem-slab")]
+    slab::main();
+
+    #[cfg(feature = "test-mem-pagetable")]
+    pagetable::main();
+
+    #[cfg(feature = "test-mem-userbuf")]
+    userbuf::main();
+
+    // ! These should fail.
+    #[cfg(any(feature = "test-mem-poison", feature = "test-mem-poison-overflow"))]
+    poison::overflow::main();
+    #[cfg(any(feature = "test-mem-poison", feature = "test-mem-poison-underflow"))]
+    poison::underflow::main();
+    #[cfg(any(
+        feature = "test-mem-poison",
+        feature = "test-mem-poison-use_after_free"
+    ))]
+    poison::use_after_free::main();
+    #[cfg(any(feature = "test-mem-poison", feature = "test-mem-poison-check"))]
+    poison::check::main();
+
     #[cfg(feature = "test-fs-inmem")]
     fs::inmem::main();
 
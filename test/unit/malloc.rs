@@ -1,7 +1,12 @@
 use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
 use core::alloc::Layout;
+use core::ptr;
 
-use crate::mem::{kalloc, malloc::Heap};
+use crate::mem::palloc::UserPool;
+use crate::mem::slab::Cache;
+use crate::mem::{kalloc, kfree, malloc::Heap, try_kalloc, Palloc, PG_SIZE};
+use crate::sbi::interrupt;
+use crate::OsError;
 
 struct T<const N: usize> {
     data: [u8; N],
@@ -127,9 +132,68 @@ fn layout() {
     // let _ = Box::new([0xccu8; 4096 * 4]);
 }
 
+fn large() {
+    let before = Palloc::usage().free;
+    unsafe {
+        // Larger than the biggest chunk of the buddy allocator
+        let size = 3 << 20;
+        let p = kalloc(size, PG_SIZE);
+        assert!(p as usize % PG_SIZE == 0);
+        assert!(Palloc::usage().free + size / PG_SIZE <= before);
+        p.write_bytes(0xcc, size);
+        assert!((0..size).step_by(PG_SIZE).all(|i| *p.add(i) == 0xcc));
+        kfree(p, size, PG_SIZE);
+    }
+    assert_eq!(before, Palloc::usage().free);
+
+    let v = alloc::vec![7u8; 5 << 20];
+    assert!(v.iter().all(|&x| x == 7));
+}
+
+/// Takes every free page, so that all allocators run dry, and recovers.
+fn oom() {
+    // Nothing else may allocate while memory is exhausted.
+    let old = interrupt::set(false);
+    let before = Palloc::usage().free;
+
+    // Taken pages are chained through their first word.
+    let mut pages: *mut usize = ptr::null_mut();
+    while let Ok(page) = unsafe { Palloc::try_alloc(1) } {
+        unsafe { (page as *mut usize).write(pages as usize) };
+        pages = page.cast();
+    }
+    assert_eq!(Palloc::usage().free, 0);
+
+    assert_eq!(unsafe { Palloc::try_alloc(1) }, Err(OsError::OutOfMemory));
+    assert_eq!(
+        unsafe { UserPool::try_alloc_pages(1) },
+        Err(OsError::OutOfMemory)
+    );
+    assert_eq!(try_kalloc(2 * PG_SIZE, PG_SIZE), Err(OsError::OutOfMemory));
+    // Page tables come from a cache, which may have free objects left.
+    assert!(Cache::<[u8; PG_SIZE]>::new("oom").alloc().is_err());
+    assert!(Vec::<u8>::new().try_reserve(2 * PG_SIZE).is_err());
+
+    while !pages.is_null() {
+        let next = unsafe { pages.read() } as *mut usize;
+        unsafe { Palloc::dealloc(pages.cast(), 1) };
+        pages = next;
+    }
+    assert_eq!(Palloc::usage().free, before);
+    interrupt::set(old);
+
+    let v = alloc::vec![7u8; 2 * PG_SIZE];
+    assert!(v.iter().all(|&x| x == 7));
+}
+
 pub fn main() {
+    // Redzones change the size class of every request.
+    #[cfg(not(feature = "mem-poison"))]
     vec_simple();
     vec_exhaustive();
+    large();
+    oom();
 
+    #[cfg(not(feature = "mem-poison"))]
     layout();
 }
@@ -0,0 +1,139 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use crate::mem::userbuf::__knrl_copy_usr;
+use crate::mem::{KernelPgTable, PTEFlags, PageTable, PhysAddr, PG_SIZE};
+
+const MEGA: usize = PG_SIZE << 9;
+const GIGA: usize = MEGA << 9;
+
+/// A range that is gigapage aligned, but ends with smaller pieces
+const VA: usize = GIGA;
+const PA: usize = 0x8000_0000;
+const SIZE: usize = GIGA + 3 * MEGA + 5 * PG_SIZE;
+
+/// Removes the mappings in the test range, so that only tables are freed.
+fn release(pt: &mut PageTable) {
+    let mut va = VA;
+    while va < VA + SIZE {
+        let (_, size) = pt.leaf(va).unwrap();
+        pt.unmap(va).unwrap();
+        va += size;
+    }
+    unsafe { pt.destroy() };
+}
+
+/// Constant data, which the kernel keeps read-only
+static CONSTANT: [u8; 4] = *b"taco";
+
+/// Whether writing a byte to `va` faults. The copy routine recovers from any
+/// fault, so it can probe kernel memory as well.
+fn write_faults(va: usize) -> bool {
+    let byte = [0u8];
+    unsafe { __knrl_copy_usr(va as *mut u8, byte.as_ptr(), 1) != 0 }
+}
+
+/// Every section of the kernel is mapped with the least rights it needs.
+fn sections() {
+    let kernel = KernelPgTable::get();
+    let flags = |va: usize| kernel.leaf(va).unwrap().0.flag();
+
+    let text = sections as usize;
+    assert!(flags(text).contains(PTEFlags::R | PTEFlags::X));
+    assert!(!flags(text).contains(PTEFlags::W));
+    assert!(write_faults(text), "kernel text is writable");
+
+    let rodata = CONSTANT.as_ptr() as usize;
+    assert!(!flags(rodata).intersects(PTEFlags::W | PTEFlags::X));
+    assert!(write_faults(rodata), "kernel constants are writable");
+
+    let heap = Box::new(0u8);
+    let data = &*heap as *const u8 as usize;
+    assert!(flags(data).contains(PTEFlags::R | PTEFlags::W));
+    assert!(!flags(data).contains(PTEFlags::X));
+    assert!(!write_faults(data));
+}
+
+/// Neighbouring pages form one range if their frames follow each other, and
+/// only [`PTEFlags::A`] and [`PTEFlags::D`] tell them apart.
+fn ranges() {
+    let rw = PTEFlags::V | PTEFlags::R | PTEFlags::W;
+    let ro = PTEFlags::V | PTEFlags::R;
+    let page = |n: usize| n * PG_SIZE;
+
+    let mut pt = KernelPgTable::clone();
+    pt.map_pages(PhysAddr::from_pa(PA), VA, page(2), rw);
+    let accessed = rw | PTEFlags::A | PTEFlags::D;
+    pt.map_pages(
+        PhysAddr::from_pa(PA + page(2)),
+        VA + page(2),
+        page(1),
+        accessed,
+    );
+    // Other flags, a frame that doesn't follow, and a hole
+    pt.map_pages(PhysAddr::from_pa(PA + page(3)), VA + page(3), page(1), ro);
+    pt.map_pages(PhysAddr::from_pa(PA + page(8)), VA + page(4), page(1), ro);
+    pt.map_pages(PhysAddr::from_pa(PA + page(9)), VA + page(6), page(1), ro);
+
+    let mut ranges = Vec::new();
+    pt.for_each_range(|start, end, pa, flags| {
+        if (VA..VA + SIZE).contains(&start) {
+            ranges.push((start, end, pa, flags));
+        }
+    });
+    assert_eq!(
+        ranges,
+        [
+            (VA, VA + page(3), PA, rw),
+            (VA + page(3), VA + page(4), PA + page(3), ro),
+            (VA + page(4), VA + page(5), PA + page(8), ro),
+            (VA + page(6), VA + page(7), PA + page(9), ro),
+        ]
+    );
+
+    for n in [0, 1, 2, 3, 4, 6] {
+        pt.unmap(VA + page(n)).unwrap();
+    }
+    unsafe { pt.destroy() };
+}
+
+pub fn main() {
+    sections();
+    ranges();
+
+    let flag = PTEFlags::V | PTEFlags::R | PTEFlags::W;
+
+    let mut huge = KernelPgTable::clone();
+    huge.map(PhysAddr::from_pa(PA), VA, SIZE, flag);
+    let mut small = KernelPgTable::clone();
+    small.map_pages(PhysAddr::from_pa(PA), VA, SIZE, flag);
+
+    // Both layouts translate every address alike.
+    for va in (VA..VA + SIZE).step_by(MEGA / 4 + PG_SIZE) {
+        let (h, hsize) = huge.leaf(va).unwrap();
+        let (s, ssize) = small.leaf(va).unwrap();
+        assert_eq!(ssize, PG_SIZE);
+        assert_eq!(h.flag(), s.flag());
+        assert_eq!(
+            h.pa().value() + va % hsize,
+            s.pa().value() + va % ssize,
+            "{:#x} is translated differently",
+            va
+        );
+    }
+    assert!(huge.leaf(VA + SIZE).is_none());
+    assert!(small.leaf(VA + SIZE).is_none());
+
+    // One gigapage, three megapages in a level-1 table and five pages in a
+    // level-0 table, against two level-1 tables and 516 level-0 tables.
+    assert_eq!(huge.leaf(VA).unwrap().1, GIGA);
+    assert_eq!(huge.leaf(VA + GIGA).unwrap().1, MEGA);
+    assert_eq!(huge.leaf(VA + GIGA + 3 * MEGA).unwrap().1, PG_SIZE);
+    assert_eq!(huge.tables(), 1 + 1 + 1);
+    assert_eq!(small.tables(), 1 + 2 + 516);
+
+    release(&mut huge);
+    release(&mut small);
+
+    kprintln!("Megapages and gigapages work.");
+}
@@ -0,0 +1,4 @@
+pub mod check;
+pub mod overflow;
+pub mod underflow;
+pub mod use_after_free;
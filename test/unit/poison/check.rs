@@ -0,0 +1,18 @@
+use crate::mem::kalloc;
+use crate::mem::malloc::poison::CHECK_INTERVAL;
+use crate::sbi::timer::{timer_elapsed, timer_ticks};
+use crate::thread;
+
+/// Overflows a block that is never freed, which the periodic check of the
+/// whole heap must catch.
+pub fn main() {
+    let p = kalloc(24, 8);
+    unsafe { p.add(24).write(0) };
+
+    let start = timer_ticks();
+    while timer_elapsed(start) <= 2 * CHECK_INTERVAL {
+        thread::schedule();
+    }
+
+    unreachable!("the heap check doesn't run");
+}
@@ -0,0 +1,10 @@
+use crate::mem::{kalloc, kfree};
+
+/// Writes one byte past a block, which must be caught on `kfree`.
+pub fn main() {
+    let p = kalloc(24, 8);
+    unsafe { p.add(24).write(0) };
+    kfree(p, 24, 8);
+
+    unreachable!("overflow is not detected");
+}
@@ -0,0 +1,11 @@
+use crate::mem::{kalloc, kfree};
+
+/// Writes one byte before a block, into its front redzone, which must be
+/// caught on `kfree`.
+pub fn main() {
+    let p = kalloc(24, 8);
+    unsafe { p.sub(1).write(0) };
+    kfree(p, 24, 8);
+
+    unreachable!("underflow is not detected");
+}
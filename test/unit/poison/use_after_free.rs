@@ -0,0 +1,12 @@
+use crate::mem::{kalloc, kfree};
+
+/// Writes to a freed block, which must be caught when the block is handed out
+/// again.
+pub fn main() {
+    let p = kalloc(24, 8);
+    kfree(p, 24, 8);
+    unsafe { p.write(0) };
+    let _ = kalloc(24, 8);
+
+    unreachable!("use after free is not detected");
+}
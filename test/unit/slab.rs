@@ -0,0 +1,110 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+use crate::mem::slab::{Cache, CacheBox};
+
+struct T<const N: usize> {
+    data: [u8; N],
+    index: usize,
+}
+
+/// Objects are packed by their exact size, and stay intact while other
+/// objects come and go.
+fn packing<const N: usize>() {
+    let cache = Cache::<T<N>>::new("packing");
+    let objs: Vec<_> = (0..100)
+        .map(|index| {
+            let obj = cache.alloc().unwrap();
+            unsafe {
+                obj.write(T {
+                    data: [index as u8; N],
+                    index,
+                })
+            };
+            obj
+        })
+        .collect();
+
+    let stats = cache.stats();
+    assert_eq!(stats.inuse, 100);
+    assert_eq!(stats.allocs, 100);
+    assert!(stats.obj_size < 2 * core::mem::size_of::<T<N>>());
+    assert!(stats.capacity >= 100);
+
+    for (index, &obj) in objs.iter().enumerate() {
+        let obj = unsafe { &*obj };
+        assert_eq!(obj.index, index);
+        assert!(obj.data.iter().all(|&b| b == index as u8));
+    }
+
+    objs.into_iter().for_each(|obj| unsafe { cache.free(obj) });
+    assert_eq!(cache.stats().inuse, 0);
+}
+
+/// Empty slabs go back to the page allocator, except for the one kept for
+/// later allocations.
+fn reclaim() {
+    let cache = Cache::<T<200>>::new("reclaim");
+    let objs: Vec<_> = (0..200).map(|_| cache.alloc().unwrap()).collect();
+    let slabs = cache.stats().slabs;
+    assert!(slabs > 1);
+
+    objs.into_iter().for_each(|obj| unsafe { cache.free(obj) });
+    let stats = cache.stats();
+    assert_eq!(stats.slabs, 1);
+    assert_eq!(stats.reclaimed, slabs - 1);
+
+    // The kept slab is reused
+    let obj = cache.alloc().unwrap();
+    assert_eq!(cache.stats().slabs, 1);
+    unsafe { cache.free(obj) };
+
+    cache.shrink();
+    assert_eq!(cache.stats().slabs, 0);
+}
+
+/// Page-sized objects, e.g. page tables, span multi-page slabs.
+fn large() {
+    #[repr(align(4096))]
+    struct Page([u8; 4096]);
+
+    let cache = Cache::<Page>::new("large");
+    let objs: Vec<_> = (0..20).map(|_| cache.alloc().unwrap()).collect();
+    assert!(objs.iter().all(|&obj| obj as usize % 4096 == 0));
+    assert!(cache.stats().slab_pages > 1);
+    objs.into_iter().for_each(|obj| unsafe { cache.free(obj) });
+}
+
+/// A boxed object is dropped and returned to its cache with the box.
+fn boxed() {
+    struct Counted<'a>(&'a AtomicUsize);
+
+    impl Drop for Counted<'_> {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, SeqCst);
+        }
+    }
+
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+    let cache: &'static Cache<Counted> = Box::leak(Box::new(Cache::new("boxed")));
+
+    let boxes: Vec<_> = (0..10)
+        .map(|_| CacheBox::try_new(cache, Counted(&DROPS)).unwrap())
+        .collect();
+    assert_eq!(cache.stats().inuse, 10);
+
+    drop(boxes);
+    assert_eq!(DROPS.load(SeqCst), 10);
+    assert_eq!(cache.stats().inuse, 0);
+}
+
+pub fn main() {
+    packing::<1>();
+    packing::<24>();
+    packing::<1030>();
+
+    reclaim();
+    large();
+    boxed();
+}
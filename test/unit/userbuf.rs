@@ -0,0 +1,91 @@
+use crate::mem::palloc::UserPool;
+use crate::mem::userbuf::{cmpxchg_user, copy_from_user, copy_to_user, strncpy_from_user, UserBuf};
+use crate::mem::{KernelPgTable, PTEFlags, PhysAddr, PG_SIZE};
+use crate::sbi::interrupt;
+use crate::OsError;
+
+use riscv::register::sstatus;
+
+/// Two user pages are mapped here, and the page after them isn't.
+const BASE: usize = 0x1000_0000;
+
+fn mapped() {
+    let src: [u8; 3 * PG_SIZE / 2] = core::array::from_fn(|i| i as u8);
+    let mut dst = [0u8; 3 * PG_SIZE / 2];
+
+    // Across the page boundary, aligned or not.
+    for addr in [BASE + PG_SIZE / 2, BASE + PG_SIZE / 2 + 3] {
+        copy_to_user(addr, &src).unwrap();
+        copy_from_user(&mut dst, addr).unwrap();
+        assert_eq!(src, dst);
+    }
+
+    let mut buf = UserBuf::new((BASE + PG_SIZE / 2 + 3) as *const u8, src.len()).unwrap();
+    let lens: [usize; 2] = [PG_SIZE / 2 - 3, PG_SIZE + 3];
+    for (page, len) in buf.pages_mut().zip(lens) {
+        let mut page = page.unwrap();
+        assert_eq!(page.len(), len);
+        page.fill(7);
+    }
+    assert!(buf
+        .pages()
+        .all(|page| page.unwrap().iter().all(|&b| b == 7)));
+
+    let string = BASE + PG_SIZE - 3;
+    copy_to_user(string, b"hello\0").unwrap();
+    let mut name = [0u8; 16];
+    assert_eq!(strncpy_from_user(&mut name, string), Ok(5));
+    assert_eq!(&name[..6], b"hello\0");
+    assert_eq!(strncpy_from_user(&mut name[..3], string), Ok(3));
+
+    let word = BASE + PG_SIZE;
+    copy_to_user(word, &5u32.to_ne_bytes()).unwrap();
+    assert_eq!(cmpxchg_user(word, 5, 7), Ok(5));
+    assert_eq!(cmpxchg_user(word, 5, 9), Ok(7));
+    let mut value = [0u8; 4];
+    copy_from_user(&mut value, word).unwrap();
+    assert_eq!(u32::from_ne_bytes(value), 7);
+
+    // The copy faults on the page after the mapped ones.
+    let end = BASE + 2 * PG_SIZE - 8;
+    assert_eq!(copy_from_user(&mut dst[..16], end), Err(OsError::BadPtr));
+
+    // User memory is out of reach again once a copy is done.
+    assert!(!sstatus::read().sum());
+}
+
+pub fn main() {
+    // Kernel pointers and overflowing ranges are refused upfront.
+    let mut dst = [0u8; 8];
+    let kernel = dst.as_ptr();
+    assert_eq!(
+        copy_from_user(&mut dst, kernel as usize),
+        Err(OsError::BadPtr)
+    );
+    assert_eq!(copy_from_user(&mut dst, usize::MAX), Err(OsError::BadPtr));
+    assert!(UserBuf::new(kernel, 8).is_err());
+
+    // Nothing is mapped in user space of a kernel thread.
+    assert_eq!(copy_from_user(&mut dst, BASE), Err(OsError::BadPtr));
+    assert_eq!(copy_to_user(BASE, &dst), Err(OsError::BadPtr));
+    assert_eq!(strncpy_from_user(&mut dst, BASE), Err(OsError::BadPtr));
+
+    // Run on a page table with user pages, which a switch would deactivate.
+    let old = interrupt::set(false);
+    let mut pt = KernelPgTable::clone();
+    for va in [BASE, BASE + PG_SIZE] {
+        let frame = unsafe { UserPool::alloc_pages(1) };
+        let flags = PTEFlags::V | PTEFlags::R | PTEFlags::W | PTEFlags::U;
+        pt.map(PhysAddr::from(frame), va, PG_SIZE, flags);
+    }
+    pt.activate();
+
+    mapped();
+
+    interrupt::set(false);
+    KernelPgTable::get().activate();
+    unsafe { pt.destroy() };
+    interrupt::set(old);
+
+    kprintln!("User memory copies work.");
+}
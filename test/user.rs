@@ -32,7 +32,7 @@ pub fn main(cmd: &str) {
     let name = argv[0].clone();
     let file = DISKFS.open(name.as_str().into()).unwrap();
 
-    let r = userproc::wait(userproc::execute(file, argv)).unwrap();
+    let r = userproc::wait(userproc::execute(file, argv, Vec::new())).unwrap();
     if KILLED_USERPROC.iter().find(|n| name.eq(**n)).is_some() {
         assert_eq!(r, KILLED_EXIT);
     } else {
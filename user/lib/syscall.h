@@ -18,3 +18,35 @@
 /* Project 4 only. */
 #define SYS_CHDIR 15 /**< Change the current directory. */
 #define SYS_MKDIR 16 /**< Create a directory. */
+
+/* Process creation by copy-on-write cloning. */
+#define SYS_FORK 17 /**< Clone the calling process. */
+
+/* Scheduling. */
+#define SYS_YIELD 18 /**< Give up the CPU to other processes. */
+
+/* Shared memory. */
+#define SYS_SHMAT 19 /**< Attach a shared memory segment. */
+#define SYS_SHMDT 20 /**< Detach a shared memory segment. */
+
+/* Heap. */
+#define SYS_SBRK 21 /**< Move the program break. */
+#define SYS_BRK 22  /**< Set the program break. */
+
+/* Debugging. */
+#define SYS_MEMMAP 23 /**< Print the memory map of this process. */
+
+/* Pipes and redirection. */
+#define SYS_PIPE 24 /**< Create a pipe. */
+#define SYS_DUP 25  /**< Duplicate a file descriptor. */
+#define SYS_DUP2 26 /**< Duplicate a file descriptor onto another one. */
+
+/* Signals. */
+#define SYS_KILL 27      /**< Send a signal to a process. */
+#define SYS_SIGACTION 28 /**< Set the handler of a signal. */
+#define SYS_SIGRETURN 29 /**< Return from a signal handler. */
+
+/* User threads. */
+#define SYS_THREAD_CREATE 30 /**< Start a thread in this process. */
+#define SYS_THREAD_EXIT 31   /**< Terminate this thread. */
+#define SYS_THREAD_JOIN 32   /**< Wait for a thread to terminate. */
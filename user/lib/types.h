@@ -12,6 +12,42 @@ typedef unsigned long size_t;
 typedef int pid_t;
 #define PID_ERROR ((pid_t)-1)
 
+/* Thread identifier. */
+typedef int tid_t;
+#define TID_ERROR ((tid_t)-1)
+
 /* Map region identifier. */
 typedef int mapid_t;
 #define MAP_FAILED ((mapid_t)-1)
+
+/* Shared memory attachment. */
+#define SHM_FAILED ((void*)-1)
+
+/* Program break. */
+#define SBRK_FAILED ((void*)-1)
+
+/* Signals. */
+typedef void (*sighandler_t)(int);
+#define SIG_DFL ((sighandler_t)0)
+#define SIG_IGN ((sighandler_t)1)
+#define SIG_ERR ((sighandler_t)-1)
+
+#define SIGINT 2
+#define SIGILL 4
+#define SIGTRAP 5
+#define SIGBUS 7
+#define SIGKILL 9
+#define SIGUSR1 10
+#define SIGSEGV 11
+#define SIGUSR2 12
+#define SIGTERM 15
+#define SIGCHLD 17
+
+/* Types of auxiliary vector entries, which follow envp on the initial stack. */
+#define AT_NULL 0
+#define AT_PHDR 3
+#define AT_PHENT 4
+#define AT_PHNUM 5
+#define AT_PAGESZ 6
+#define AT_ENTRY 9
+#define AT_RANDOM 25
@@ -3,9 +3,10 @@
 #include "user.h"
 
 // wrapper so that it's OK if main() does not call exit().
-void _main() {
-    extern void main();
-    main();
+// The kernel passes argc, argv and envp in a0, a1 and a2.
+void _main(int argc, char* argv[], char* envp[]) {
+    extern void main(int, char*[], char*[]);
+    main(argc, argv, envp);
     exit(NORMAL_EXIT);
 }
 
@@ -182,3 +183,23 @@ inline uint64 r_sp() {
     asm volatile("mv %0, sp" : "=r"(x));
     return x;
 }
+
+// Handlers return into `sigreturn`, which resumes the interrupted code.
+sighandler_t signal(int sig, sighandler_t handler) {
+    return sigaction(sig, handler, sigreturn);
+}
+
+tid_t __thread_create(void (*entry)(uint64*), uint64* arg, void* stack);
+
+// A thread starts here with the function and its argument on top of its stack.
+static void thread_start(uint64* args) {
+    int (*fn)(void*) = (int (*)(void*))args[0];
+    thread_exit(fn((void*)args[1]));
+}
+
+tid_t thread_create(int (*fn)(void*), void* arg, void* stack) {
+    uint64* args = (uint64*)ROUND_DOWN(stack, 16) - 2;
+    args[0] = (uint64)fn;
+    args[1] = (uint64)arg;
+    return __thread_create(thread_start, args, args);
+}
@@ -0,0 +1,110 @@
+/* A first-fit memory allocator on top of sbrk.
+
+   Free blocks are kept in a list sorted by address, so that neighbours are
+   merged when a block is freed. A free block that ends at the program break
+   and spans several pages is given back to the kernel. */
+
+#include "user.h"
+
+typedef struct header {
+    struct header* next; /* Next free block, by address. */
+    uint64 size;         /* In units of the header, the header included. */
+} header;
+
+/* Bytes to ask the kernel for at least. */
+#define MIN_GROW 4096
+/* Bytes at the top of the heap that are given back. */
+#define TRIM 16384
+
+static header* freep;
+
+/* Gives the free block at the top of the heap back, if it's large enough. */
+static void trim(void) {
+    header *prev = NULL, *p = freep;
+
+    if (p == NULL) return;
+    while (p->next != NULL) {
+        prev = p;
+        p = p->next;
+    }
+
+    if ((void*)(p + p->size) != sbrk(0) || p->size * sizeof(header) < TRIM) return;
+
+    if (prev != NULL)
+        prev->next = NULL;
+    else
+        freep = NULL;
+    sbrk(-(long)(p->size * sizeof(header)));
+}
+
+/* Puts a block into the free list, merging it with its neighbours. */
+static void insert(header* b) {
+    header *prev = NULL, *next = freep;
+
+    while (next != NULL && next < b) {
+        prev = next;
+        next = next->next;
+    }
+
+    b->next = next;
+    if (next != NULL && b + b->size == next) {
+        b->size += next->size;
+        b->next = next->next;
+    }
+
+    if (prev != NULL && prev + prev->size == b) {
+        prev->size += b->size;
+        prev->next = b->next;
+    } else if (prev != NULL) {
+        prev->next = b;
+    } else {
+        freep = b;
+    }
+}
+
+void free(void* ap) {
+    if (ap == NULL) return;
+    insert((header*)ap - 1);
+    trim();
+}
+
+/* Grows the heap by at least `units` headers. */
+static int morecore(uint64 units) {
+    uint64 brk = (uint64)sbrk(0);
+    uint64 bytes = units * sizeof(header);
+    header* h;
+
+    /* Keep blocks aligned to their headers. */
+    if (brk % sizeof(header) != 0 && sbrk(sizeof(header) - brk % sizeof(header)) == SBRK_FAILED)
+        return 0;
+
+    if (bytes < MIN_GROW) bytes = MIN_GROW;
+    if ((h = sbrk(bytes)) == SBRK_FAILED) return 0;
+
+    h->size = bytes / sizeof(header);
+    insert(h);
+    return 1;
+}
+
+void* malloc(uint nbytes) {
+    uint64 units = (nbytes + sizeof(header) - 1) / sizeof(header) + 1;
+    header **pp, *p;
+
+    for (;;) {
+        for (pp = &freep; (p = *pp) != NULL; pp = &p->next) {
+            if (p->size == units) {
+                *pp = p->next;
+                return p + 1;
+            }
+            if (p->size > units) {
+                /* Hand out the tail of the block. */
+                p->size -= units;
+                p += p->size;
+                p->size = units;
+                return p + 1;
+            }
+        }
+
+        if (!morecore(units)) return NULL;
+    }
+}
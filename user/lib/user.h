@@ -42,6 +42,22 @@ int mmap(int fd, void* addr);
 void munmap(int mapid);
 int chdir(const char* dir);
 int mkdir(const char* dir);
+pid_t fork(void);
+void yield(void);
+void* shmat(int key, uint size, void* addr);
+int shmdt(void* addr);
+void* sbrk(long increment);
+int brk(void* addr);
+int memmap(void);
+int pipe(int fds[2]);
+int dup(int fd);
+int dup2(int fd, int newfd);
+int kill(pid_t pid, int sig);
+sighandler_t sigaction(int sig, sighandler_t handler, void (*restorer)(void));
+void sigreturn(void);
+tid_t thread_create(int (*fn)(void*), void* arg, void* stack);
+void thread_exit(int value);
+int thread_join(tid_t tid);
 
 // ulib.c
 void fprintf(int fd, const char* fmt, ...);
@@ -57,5 +73,10 @@ void* memcpy(void*, const void*, size_t);
 void check_file(const char*, const void* buf, size_t);
 void check_file_handle(int fd, const char* file_name, const void* buf_, size_t size);
 uint64 r_sp();
+sighandler_t signal(int sig, sighandler_t handler);
+
+// umalloc.c
+void* malloc(uint);
+void free(void*);
 
 #endif
@@ -4,11 +4,14 @@ print "# generated by usys.pl - do not edit\n";
 
 print "#include \"syscall.h\"\n";
 
+# An optional second argument names the stub, if a C wrapper takes the name
+# of the syscall.
 sub entry {
     my $name = shift;
     my $uname = uc($name);
-    print ".global $name\n";
-    print "$name:\n";
+    my $stub = shift || $name;
+    print ".global $stub\n";
+    print "$stub:\n";
     print " li a7, SYS_$uname\n";
     print " ecall\n";
     print " ret\n";
@@ -30,3 +33,19 @@ entry("mmap");
 entry("munmap");
 entry("chdir");
 entry("mkdir");
+entry("fork");
+entry("yield");
+entry("shmat");
+entry("shmdt");
+entry("sbrk");
+entry("brk");
+entry("memmap");
+entry("pipe");
+entry("dup");
+entry("dup2");
+entry("kill");
+entry("sigaction");
+entry("sigreturn");
+entry("thread_create", "__thread_create");
+entry("thread_exit");
+entry("thread_join");
@@ -0,0 +1,32 @@
+/** Finds the environment and the auxiliary vector after the arguments, as
+   laid out on the initial stack. */
+
+#include "user.h"
+
+void main(int argc, char* argv[], char* envp[]) {
+    extern void _main();
+    char** p = argv + argc;
+    uint64* auxv;
+    uint64 pagesz = 0, entry = 0, phnum = 0;
+    uchar* random = NULL;
+
+    assert(argc == 1 && strcmp(argv[0], "args-auxv") == 0);
+    assert(*p++ == NULL, "argv ends with NULL");
+    assert(p == envp, "envp follows argv");
+    while (*p) p++;
+
+    for (auxv = (uint64*)(p + 1); auxv[0] != AT_NULL; auxv += 2) {
+        switch (auxv[0]) {
+            case AT_PAGESZ: pagesz = auxv[1]; break;
+            case AT_ENTRY: entry = auxv[1]; break;
+            case AT_PHNUM: phnum = auxv[1]; break;
+            case AT_RANDOM: random = (uchar*)auxv[1]; break;
+        }
+    }
+
+    assert(pagesz == 4096);
+    assert(entry == (uint64)_main, "entry is %p", (void*)entry);
+    assert(phnum > 0);
+    assert(random != NULL);
+    assert(memcmp(random, "\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0", 16) != 0, "random bytes");
+}
@@ -0,0 +1,46 @@
+/** Reads a large BSS region, whose pages all map the shared zero frame, and
+   writes to some of them. A written page gets a private copy, and the pages
+   around it must stay zeroed, in the parent as well as in a forked child. */
+
+#include "user.h"
+
+#define PAGE 4096
+#define PAGES 64
+
+static char bss[PAGES * PAGE];
+
+static void check_zero(int from, int to) {
+    int i;
+
+    for (i = from * PAGE; i < to * PAGE; i++) assert(bss[i] == 0, "bss[%d] is zeroed", i);
+}
+
+void main() {
+    pid_t pid;
+    char* heap;
+    int i;
+
+    check_zero(0, PAGES);
+
+    bss[10 * PAGE] = 'p';
+    check_zero(0, 10);
+    check_zero(11, PAGES);
+
+    assert((pid = fork()) != PID_ERROR);
+    if (pid == 0) {
+        check_zero(0, 10);
+        bss[20 * PAGE] = 'c';
+        assert(bss[10 * PAGE] == 'p');
+        check_zero(21, PAGES);
+        exit(46);
+    }
+
+    assert(wait(pid) == 46);
+    assert(bss[20 * PAGE] == 0, "the child's write stays private");
+
+    /* New heap pages are zero pages too. */
+    assert((heap = sbrk(4 * PAGE)) != SBRK_FAILED);
+    for (i = 0; i < 4 * PAGE; i++) assert(heap[i] == 0);
+    heap[PAGE] = 'h';
+    assert(heap[0] == 0 && heap[2 * PAGE] == 0);
+}
@@ -0,0 +1,27 @@
+/** Forks a child that writes to pages shared copy-on-write with its parent.
+   The parent must not observe any of the child's writes. */
+
+#include "user.h"
+
+static char data[8192] = "parent";
+static char bss[8192];
+
+void main() {
+    pid_t pid;
+
+    bss[4096] = 'p';
+
+    assert((pid = fork()) != PID_ERROR);
+    if (pid == 0) {
+        assert(strcmp(data, "parent") == 0);
+        assert(bss[4096] == 'p');
+
+        strcpy(data, "child");
+        bss[4096] = 'c';
+        exit(81);
+    }
+
+    assert(wait(pid) == 81);
+    assert(strcmp(data, "parent") == 0);
+    assert(bss[4096] == 'p');
+}
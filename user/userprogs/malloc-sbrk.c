@@ -0,0 +1,70 @@
+/** Grows and shrinks the heap with sbrk, then runs malloc on top of it.
+
+   Pages above the break are zeroed when the break grows over them again, and
+   malloc hands large free blocks at the top of the heap back to the kernel. */
+
+#include "user.h"
+
+#define PAGE 4096
+#define BLOCKS 64
+
+static void sbrk_pages() {
+    char *base, *p;
+    int i;
+
+    /* Start on a page boundary, so that only heap pages are given back. */
+    base = sbrk(0);
+    assert(sbrk(ROUND_UP(base, PAGE) - (uint64)base) == base);
+    base = sbrk(0);
+
+    assert((p = sbrk(3 * PAGE)) == base);
+    assert(sbrk(0) == base + 3 * PAGE);
+    for (i = 0; i < 3 * PAGE; i++) assert(p[i] == 0, "new heap is zeroed");
+    memset(p, 'x', 3 * PAGE);
+
+    assert(sbrk(-3 * PAGE) == base + 3 * PAGE);
+    assert(sbrk(3 * PAGE) == base);
+    for (i = 0; i < 3 * PAGE; i++) assert(p[i] == 0, "shrunk pages are given back");
+    assert(brk(base) == 0);
+
+    /* The heap can neither go below its start nor reach the stack. */
+    assert(sbrk(-(long)base) == SBRK_FAILED);
+    assert(sbrk(0x7fffffffffL) == SBRK_FAILED);
+    assert(sbrk(0) == base);
+}
+
+static void malloc_blocks() {
+    char* blocks[BLOCKS];
+    char* top = sbrk(0);
+    uint size;
+    int i, j;
+
+    for (i = 0; i < BLOCKS; i++) {
+        size = (i * 37) % 3000 + 1;
+        assert((blocks[i] = malloc(size)) != NULL);
+        assert((uint64)blocks[i] % 16 == 0);
+        memset(blocks[i], i, size);
+    }
+
+    /* Free every other block, and fill the holes again. */
+    for (i = 0; i < BLOCKS; i += 2) free(blocks[i]);
+    for (i = 0; i < BLOCKS; i += 2) {
+        size = (i * 37) % 3000 + 1;
+        assert((blocks[i] = malloc(size)) != NULL);
+        memset(blocks[i], i, size);
+    }
+
+    for (i = 0; i < BLOCKS; i++) {
+        size = (i * 37) % 3000 + 1;
+        for (j = 0; j < size; j++) assert(blocks[i][j] == (char)i, "block %d is intact", i);
+    }
+
+    /* A large block goes back to the kernel as a whole. */
+    for (i = 0; i < BLOCKS; i++) free(blocks[i]);
+    assert(sbrk(0) <= top + PAGE, "freed heap is given back");
+}
+
+void main() {
+    sbrk_pages();
+    malloc_blocks();
+}
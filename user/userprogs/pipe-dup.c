@@ -0,0 +1,47 @@
+/** Connects a forked child to its parent with a pipe. The child prints to its
+   redirected standard output, and the parent reads it until end of file.
+   Writing to a pipe without readers fails. */
+
+#include "user.h"
+
+#define MESSAGE "hello through a pipe"
+
+void main() {
+    int fds[2], fd, n, total = 0;
+    char buf[64];
+    pid_t pid;
+
+    assert(pipe(fds) == 0);
+    assert((pid = fork()) != PID_ERROR);
+    if (pid == 0) {
+        close(fds[0]);
+        assert(dup2(fds[1], 1) == 1);
+        close(fds[1]);
+        printf(MESSAGE);
+        exit(47);
+    }
+
+    close(fds[1]);
+    while ((n = read(fds[0], buf + total, sizeof(buf) - 1 - total)) > 0) total += n;
+    assert(n == 0, "a closed write end gives end of file");
+    buf[total] = '\0';
+    assert(strcmp(buf, MESSAGE) == 0, "read \"%s\" from the pipe", buf);
+    assert(wait(pid) == 47);
+    close(fds[0]);
+
+    /* Without readers, writes fail. */
+    assert(pipe(fds) == 0);
+    close(fds[0]);
+    assert(write(fds[1], "x", 1) == -1);
+    close(fds[1]);
+
+    /* dup takes the lowest free descriptor, for the same end of the pipe. */
+    assert(pipe(fds) == 0);
+    assert((fd = dup(fds[1])) > fds[1]);
+    assert(write(fd, "ab", 2) == 2);
+    close(fd);
+    close(fds[1]);
+    assert(read(fds[0], buf, sizeof(buf)) == 2 && memcmp(buf, "ab", 2) == 0);
+    assert(read(fds[0], buf, sizeof(buf)) == 0);
+    close(fds[0]);
+}
@@ -0,0 +1,48 @@
+/** Shares a segment between a parent and its forked child. The child sees
+   the parent's writes through the inherited attachment, and the parent sees
+   the child's writes through a second attachment of the same key. Once both
+   detach, the key names a new, zeroed segment. */
+
+#include "user.h"
+
+#define KEY 42
+#define SIZE 8192
+
+void main() {
+    char* first = (char*)0x10000000;
+    char* second = (char*)0x20000000;
+    pid_t pid;
+
+    assert(shmat(KEY, SIZE, first) == first);
+    assert(first[0] == 0 && first[SIZE - 1] == 0, "a new segment is zeroed");
+    strcpy(first, "parent");
+    first[4096] = 'p';
+
+    /* Only the first attachment decides the size. */
+    assert(shmat(KEY, SIZE * 2, second) == SHM_FAILED);
+    assert(shmat(KEY, SIZE, (void*)0x20000123) == SHM_FAILED);
+    assert(shmat(KEY, SIZE, first + 4096) == SHM_FAILED, "overlaps the first attachment");
+
+    assert((pid = fork()) != PID_ERROR);
+    if (pid == 0) {
+        assert(strcmp(first, "parent") == 0);
+        assert(first[4096] == 'p');
+
+        assert(shmdt(first) == 0);
+        assert(shmat(KEY, SIZE, second) == second);
+        strcpy(second, "child");
+        second[4096] = 'c';
+        exit(81);
+    }
+
+    assert(wait(pid) == 81);
+    assert(strcmp(first, "child") == 0);
+    assert(first[4096] == 'c');
+
+    assert(shmdt(first) == 0);
+    assert(shmdt(first) == -1, "detached twice");
+
+    assert(shmat(KEY, SIZE, second) == second);
+    assert(second[0] == 0, "the segment is gone after the last detach");
+    assert(shmdt(second) == 0);
+}
@@ -0,0 +1,77 @@
+/** Sends signals with "kill" and catches them with handlers, which return
+   to the interrupted code. Faults raise SIGSEGV, SIGILL and SIGTRAP, and
+   signals left to their default action terminate the process. */
+
+#include "user.h"
+
+static volatile int caught;
+
+static void on_usr1(int sig) {
+    assert(sig == SIGUSR1);
+    caught++;
+}
+
+static void on_segv(int sig) {
+    assert(sig == SIGSEGV);
+    exit(48);
+}
+
+static void on_ill(int sig) {
+    assert(sig == SIGILL);
+    exit(49);
+}
+
+static void on_trap(int sig) {
+    assert(sig == SIGTRAP);
+    exit(50);
+}
+
+void main() {
+    pid_t pid;
+
+    /* A handler runs and returns to where the signal arrived. */
+    assert(signal(SIGUSR1, on_usr1) == SIG_DFL);
+    assert(kill(0, SIGUSR1) == 0);
+    assert(caught == 1, "the handler ran once");
+
+    /* Ignored signals have no effect, and SIGKILL can't be caught. */
+    assert(signal(SIGUSR2, SIG_IGN) == SIG_DFL);
+    assert(kill(0, SIGUSR2) == 0);
+    assert(signal(SIGKILL, on_usr1) == SIG_ERR);
+    assert(kill(0, 32) == -1);
+
+    /* A bad access raises SIGSEGV. */
+    assert((pid = fork()) != PID_ERROR);
+    if (pid == 0) {
+        signal(SIGSEGV, on_segv);
+        caught = *(volatile int*)NULL;
+        panic("should have exited");
+    }
+    assert(wait(pid) == 48);
+
+    /* An illegal instruction raises SIGILL. */
+    assert((pid = fork()) != PID_ERROR);
+    if (pid == 0) {
+        signal(SIGILL, on_ill);
+        asm volatile(".word 0");
+        panic("should have exited");
+    }
+    assert(wait(pid) == 49);
+
+    /* A breakpoint raises SIGTRAP. */
+    assert((pid = fork()) != PID_ERROR);
+    if (pid == 0) {
+        signal(SIGTRAP, on_trap);
+        asm volatile("ebreak");
+        panic("should have exited");
+    }
+    assert(wait(pid) == 50);
+
+    /* The default action of SIGTERM terminates the process. */
+    assert((pid = fork()) != PID_ERROR);
+    if (pid == 0) {
+        for (;;) yield();
+    }
+    assert(kill(pid, SIGTERM) == 0);
+    assert(wait(pid) == -1);
+}
@@ -0,0 +1,75 @@
+/** Runs threads in one process. They share memory and open files, join
+   returns their exit values, and exit of the process ends all of them. */
+
+#include "user.h"
+
+#define THREADS 4
+#define STACK 4096
+
+static char stacks[THREADS][STACK] __attribute__((aligned(16)));
+static int slots[THREADS];
+static int fds[2];
+
+static int square(void* arg) {
+    int i = (int)(uint64)arg;
+
+    slots[i] = i * i;
+    return i + 100;
+}
+
+static int writer(void* arg) {
+    assert(write(fds[1], arg, strlen(arg)) == strlen(arg));
+    close(fds[1]);
+    return 0;
+}
+
+static int spin(void* arg) {
+    for (;;) yield();
+}
+
+static int join(void* arg) {
+    thread_join((tid_t)(uint64)arg);
+    panic("should have been ended by exit");
+}
+
+void main() {
+    tid_t tids[THREADS];
+    char buf[16];
+    pid_t pid;
+    int i;
+
+    /* Threads write to memory of the process, and leave exit values. */
+    for (i = 0; i < THREADS; i++)
+        assert((tids[i] = thread_create(square, (void*)(uint64)i, stacks[i] + STACK)) != TID_ERROR);
+    for (i = 0; i < THREADS; i++) {
+        assert(thread_join(tids[i]) == i + 100);
+        assert(slots[i] == i * i, "slot %d is written", i);
+    }
+    assert(thread_join(tids[0]) == -1, "a thread is joined once");
+
+    /* Threads share the descriptor table. */
+    assert(pipe(fds) == 0);
+    assert((tids[0] = thread_create(writer, "shared", stacks[0] + STACK)) != TID_ERROR);
+    assert(thread_join(tids[0]) == 0);
+    assert(read(fds[0], buf, sizeof(buf)) == 6 && memcmp(buf, "shared", 6) == 0);
+    assert(read(fds[0], buf, sizeof(buf)) == 0, "the thread closed the write end");
+    close(fds[0]);
+
+    /* Exiting the process ends the threads still running. */
+    assert((pid = fork()) != PID_ERROR);
+    if (pid == 0) {
+        assert(thread_create(spin, NULL, stacks[0] + STACK) != TID_ERROR);
+        exit(49);
+    }
+    assert(wait(pid) == 49);
+
+    /* So does it end a thread blocked in join. */
+    assert((pid = fork()) != PID_ERROR);
+    if (pid == 0) {
+        assert((tids[0] = thread_create(spin, NULL, stacks[0] + STACK)) != TID_ERROR);
+        assert(thread_create(join, (void*)(uint64)tids[0], stacks[1] + STACK) != TID_ERROR);
+        yield();
+        exit(50);
+    }
+    assert(wait(pid) == 50);
+}